@@ -0,0 +1,68 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{merge::Merge, FromEnv};
+use crate::var;
+use serde::{Deserialize, Serialize};
+
+/// Configuration for attachments that are sent alongside an email.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    /// Maximum size, in bytes, that a single attachment can be. Default is 10 MiB.
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+
+    /// Maximum size, in bytes, that all attachments in a single email can be. Default
+    /// is 25 MiB, which most email providers also limit to.
+    #[serde(default = "default_max_total_size")]
+    pub max_total_size: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_size: default_max_size(),
+            max_total_size: default_max_total_size(),
+        }
+    }
+}
+
+impl FromEnv for Config {
+    type Output = Config;
+
+    fn from_env() -> Self::Output {
+        Config {
+            max_size: var!("EMAILS_ATTACHMENTS_MAX_SIZE", to: u64, or_else: default_max_size()),
+            max_total_size: var!("EMAILS_ATTACHMENTS_MAX_TOTAL_SIZE", to: u64, or_else: default_max_total_size()),
+        }
+    }
+}
+
+impl Merge for Config {
+    fn merge(&mut self, other: Self) {
+        self.max_size.merge(other.max_size);
+        self.max_total_size.merge(other.max_total_size);
+    }
+}
+
+#[inline(always)]
+const fn default_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+#[inline(always)]
+const fn default_max_total_size() -> u64 {
+    25 * 1024 * 1024
+}
@@ -0,0 +1,68 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{merge::Merge, FromEnv};
+use crate::var;
+use serde::{Deserialize, Serialize};
+
+/// Configuration for emails that are sent with the `SendBatch` method.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    /// Maximum amount of emails that a single batch can have. Larger batches are
+    /// rejected, so they should be split up by the caller. Default is 1,000.
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+
+    /// How many emails in a batch are delivered at the same time. Default is 10.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: u16,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_size: default_max_size(),
+            concurrency: default_concurrency(),
+        }
+    }
+}
+
+impl FromEnv for Config {
+    type Output = Config;
+
+    fn from_env() -> Self::Output {
+        Config {
+            max_size: var!("EMAILS_BATCH_MAX_SIZE", to: u64, or_else: default_max_size()),
+            concurrency: var!("EMAILS_BATCH_CONCURRENCY", to: u16, or_else: default_concurrency()),
+        }
+    }
+}
+
+impl Merge for Config {
+    fn merge(&mut self, other: Self) {
+        self.max_size.merge(other.max_size);
+        self.concurrency.merge(other.concurrency);
+    }
+}
+
+#[inline(always)]
+const fn default_max_size() -> u64 {
+    1000
+}
+
+#[inline(always)]
+const fn default_concurrency() -> u16 {
+    10
+}
@@ -0,0 +1,78 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{merge::Merge, FromEnv};
+use crate::var;
+use serde::{Deserialize, Serialize};
+
+/// Configuration for the cache of compiled templates.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    /// Maximum amount of compiled templates to keep, where the least recently used template
+    /// is evicted first. Setting this to `0` disables the cache. Default is `128`.
+    #[serde(default = "default_max_templates")]
+    pub max_templates: u64,
+
+    /// Time, in seconds, that a compiled template is kept for. Templates are also evicted as
+    /// soon as they change when the template resolver can report changes, so this mostly bounds
+    /// how stale templates from other resolvers (i.e, S3) can be. Setting this to `0` keeps
+    /// templates until they change or are evicted. Default is `300` seconds (5 minutes).
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_templates: default_max_templates(),
+            ttl: default_ttl(),
+        }
+    }
+}
+
+impl FromEnv for Config {
+    type Output = Config;
+
+    fn from_env() -> Self::Output {
+        Config {
+            max_templates: var!("EMAILS_CACHE_MAX_TEMPLATES", to: u64, or_else: default_max_templates()),
+            ttl: var!("EMAILS_CACHE_TTL", to: u64, or_else: default_ttl()),
+        }
+    }
+}
+
+// `0` has a meaning for both of these, so they are merged unless they are the default rather
+// than unless they are zero, which is what `u64`'s `Merge` implementation does.
+impl Merge for Config {
+    fn merge(&mut self, other: Self) {
+        if other.max_templates != default_max_templates() {
+            self.max_templates = other.max_templates;
+        }
+
+        if other.ttl != default_ttl() {
+            self.ttl = other.ttl;
+        }
+    }
+}
+
+#[inline(always)]
+const fn default_max_templates() -> u64 {
+    128
+}
+
+#[inline(always)]
+const fn default_ttl() -> u64 {
+    300
+}
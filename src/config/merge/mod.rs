@@ -66,31 +66,39 @@ impl<T> Merge for Option<T> {
     }
 }
 
-impl Merge for u16 {
-    fn merge(&mut self, other: Self) {
-        // don't override if both are zero
-        if *self == 0 && other == 0 {
-            return;
-        }
+macro_rules! impl_merge_for_unsigned {
+    ($($ty:ty),*) => {
+        $(
+            impl Merge for $ty {
+                fn merge(&mut self, other: Self) {
+                    // don't override if both are zero
+                    if *self == 0 && other == 0 {
+                        return;
+                    }
 
-        // override if `other` is nonzero and self is zero
-        if *self == 0 && other > 0 {
-            *self = other;
-            return;
-        }
+                    // override if `other` is nonzero and self is zero
+                    if *self == 0 && other > 0 {
+                        *self = other;
+                        return;
+                    }
 
-        // don't override if self is nonzero and other is zero
-        if *self != 0 && other == 0 {
-            return;
-        }
+                    // don't override if self is nonzero and other is zero
+                    if *self != 0 && other == 0 {
+                        return;
+                    }
 
-        // fallback: comparison
-        if *self != other {
-            *self = other;
-        }
-    }
+                    // fallback: comparison
+                    if *self != other {
+                        *self = other;
+                    }
+                }
+            }
+        )*
+    };
 }
 
+impl_merge_for_unsigned!(u16, u64);
+
 impl Merge for bool {
     fn merge(&mut self, other: Self) {
         if !*self && !other {
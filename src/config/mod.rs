@@ -13,9 +13,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod attachments;
+mod batch;
+pub mod cache;
 mod logging;
 mod macros;
 pub mod merge;
+pub mod queue;
 mod server;
 mod smtp;
 
@@ -64,7 +68,7 @@ pub struct Config {
     pub sentry_dsn: Option<String>,
 
     /// Configuration to resolve templates from.
-    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
     pub templates: templates::Config,
 
     /// Configuration for the logging system.
@@ -78,6 +82,22 @@ pub struct Config {
     /// Configuration to connect to a SMTP server.
     #[serde(default)]
     pub smtp: smtp::Config,
+
+    /// Configuration for attachments that are sent alongside an email.
+    #[serde(default)]
+    pub attachments: attachments::Config,
+
+    /// Configuration for emails that are sent with the `SendBatch` method.
+    #[serde(default)]
+    pub batch: batch::Config,
+
+    /// Configuration for the delivery queue.
+    #[serde(default)]
+    pub queue: queue::Config,
+
+    /// Configuration for the cache of compiled templates.
+    #[serde(default)]
+    pub cache: cache::Config,
 }
 
 impl TryFromEnv for Config {
@@ -91,6 +111,10 @@ impl TryFromEnv for Config {
             logging: logging::Config::try_from_env()?,
             server: server::Config::try_from_env()?,
             smtp: smtp::Config::try_from_env()?,
+            attachments: attachments::Config::try_from_env()?,
+            batch: batch::Config::try_from_env()?,
+            queue: queue::Config::try_from_env()?,
+            cache: cache::Config::try_from_env()?,
         })
     }
 }
@@ -98,8 +122,78 @@ impl TryFromEnv for Config {
 impl Merge for Config {
     fn merge(&mut self, other: Self) {
         self.sentry_dsn.merge(other.sentry_dsn);
+        self.templates.merge(other.templates);
         self.logging.merge(other.logging);
         self.server.merge(other.server);
         self.smtp.merge(other.smtp);
+        self.attachments.merge(other.attachments);
+        self.batch.merge(other.batch);
+        self.queue.merge(other.queue);
+        self.cache.merge(other.cache);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge::Merge, Config, TryFromEnv};
+    use crate::templates;
+
+    /// Merges `env` and `file` the same way `resolve_config` does.
+    fn resolve(env: Config, file: &str) -> Config {
+        let mut config = Config::default();
+        config.merge(env);
+        config.merge(serde_yaml::from_str(file).unwrap());
+
+        config
+    }
+
+    #[test]
+    fn templates_are_merged() {
+        let config = resolve(
+            Config::default(),
+            "templates:\n    kubernetes:\n        namespace: emails\n",
+        );
+
+        assert!(matches!(
+            config.templates,
+            templates::Config::Kubernetes(ref kubernetes) if kubernetes.namespace.as_deref() == Some("emails")
+        ));
+
+        std::env::set_var("EMAILS_TEMPLATE_RESOLVER", "kubernetes,fs");
+        let env = Config::try_from_env();
+        std::env::remove_var("EMAILS_TEMPLATE_RESOLVER");
+
+        // a file without a `templates` block keeps the resolvers from the environment
+        let config = resolve(env.unwrap(), "sentry_dsn: null\n");
+        assert!(matches!(config.templates, templates::Config::Chain(ref chain) if chain.len() == 2));
+    }
+
+    #[test]
+    fn cache_can_be_disabled() {
+        let config = resolve(Config::default(), "cache:\n    max_templates: 0\n    ttl: 0\n");
+        assert_eq!((config.cache.max_templates, config.cache.ttl), (0, 0));
+
+        let mut env = Config::default();
+        env.cache.max_templates = 0;
+
+        let config = resolve(env, "cache:\n    ttl: 60\n");
+        assert_eq!((config.cache.max_templates, config.cache.ttl), (0, 60));
+    }
+
+    #[test]
+    fn queue_retention_can_be_zero() {
+        let config = resolve(
+            Config::default(),
+            "queue:\n    retention: 0\n    dead_letter_retention: 0\n    retry:\n        initial_delay: 0\n",
+        );
+
+        assert_eq!((config.queue.retention, config.queue.dead_letter_retention), (0, 0));
+        assert_eq!(config.queue.retry.initial_delay, 0);
+
+        let mut env = Config::default();
+        env.queue.retention = 0;
+
+        let config = resolve(env, "queue:\n    dead_letter_retention: 60\n");
+        assert_eq!((config.queue.retention, config.queue.dead_letter_retention), (0, 60));
     }
 }
@@ -0,0 +1,182 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{merge::Merge, FromEnv};
+use crate::var;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Configuration for the delivery queue, which delivers emails in the background
+/// when a request asks for it to be queued.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    /// Amount of background workers that deliver queued emails concurrently. Default is `4`.
+    #[serde(default = "default_workers")]
+    pub workers: u16,
+
+    /// Directory to keep queued messages in, so that they are not lost when the service
+    /// restarts. If this isn't set, then queued messages are only kept in memory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Configures how emails are retried when they couldn't be delivered because of
+    /// a transient failure.
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Time, in seconds, that delivered and cancelled messages are kept for, so that their
+    /// status can still be looked up. Setting this to `0` keeps nothing. Default is `86400`
+    /// seconds (1 day).
+    #[serde(default = "default_retention")]
+    pub retention: u64,
+
+    /// Time, in seconds, that dead letters are kept for before they are discarded. Setting
+    /// this to `0` keeps nothing. Default is `604800` seconds (7 days).
+    #[serde(default = "default_dead_letter_retention")]
+    pub dead_letter_retention: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            workers: default_workers(),
+            data_dir: None,
+            retry: RetryConfig::default(),
+            retention: default_retention(),
+            dead_letter_retention: default_dead_letter_retention(),
+        }
+    }
+}
+
+impl FromEnv for Config {
+    type Output = Config;
+
+    fn from_env() -> Self::Output {
+        Config {
+            workers: var!("EMAILS_QUEUE_WORKERS", to: u16, or_else: default_workers()),
+            data_dir: var!("EMAILS_QUEUE_DATA_DIR", to: PathBuf, is_optional: true),
+            retry: RetryConfig::from_env(),
+            retention: var!("EMAILS_QUEUE_RETENTION", to: u64, or_else: default_retention()),
+            dead_letter_retention: var!(
+                "EMAILS_QUEUE_DEAD_LETTER_RETENTION",
+                to: u64,
+                or_else: default_dead_letter_retention()
+            ),
+        }
+    }
+}
+
+// `0` has a meaning for the retention periods (keep nothing), so they are merged unless they
+// are the default rather than unless they are zero, like the cache's configuration.
+impl Merge for Config {
+    fn merge(&mut self, other: Self) {
+        self.workers.merge(other.workers);
+        self.data_dir.merge(other.data_dir);
+        self.retry.merge(other.retry);
+
+        if other.retention != default_retention() {
+            self.retention = other.retention;
+        }
+
+        if other.dead_letter_retention != default_dead_letter_retention() {
+            self.dead_letter_retention = other.dead_letter_retention;
+        }
+    }
+}
+
+#[inline(always)]
+const fn default_workers() -> u16 {
+    4
+}
+
+#[inline(always)]
+const fn default_retention() -> u64 {
+    86400
+}
+
+#[inline(always)]
+const fn default_dead_letter_retention() -> u64 {
+    7 * 86400
+}
+
+/// Configuration for retrying emails with exponential backoff. The delay between each attempt
+/// doubles, starting from `initial_delay` up to `max_delay`, and a random jitter is applied
+/// so that deferred emails don't all hit the SMTP server at the same time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// Maximum amount of delivery attempts before an email is considered failed. Default is `5`.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u16,
+
+    /// Delay, in seconds, before the first retry. Default is `30` seconds.
+    #[serde(default = "default_initial_delay")]
+    pub initial_delay: u64,
+
+    /// Maximum delay, in seconds, between two attempts. Default is `3600` seconds (1 hour).
+    #[serde(default = "default_max_delay")]
+    pub max_delay: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            max_attempts: default_max_attempts(),
+            initial_delay: default_initial_delay(),
+            max_delay: default_max_delay(),
+        }
+    }
+}
+
+impl FromEnv for RetryConfig {
+    type Output = RetryConfig;
+
+    fn from_env() -> Self::Output {
+        RetryConfig {
+            max_attempts: var!("EMAILS_QUEUE_RETRY_MAX_ATTEMPTS", to: u16, or_else: default_max_attempts()),
+            initial_delay: var!("EMAILS_QUEUE_RETRY_INITIAL_DELAY", to: u64, or_else: default_initial_delay()),
+            max_delay: var!("EMAILS_QUEUE_RETRY_MAX_DELAY", to: u64, or_else: default_max_delay()),
+        }
+    }
+}
+
+// delays of `0` retry right away, so they are merged unless they are the default as well
+impl Merge for RetryConfig {
+    fn merge(&mut self, other: Self) {
+        self.max_attempts.merge(other.max_attempts);
+
+        if other.initial_delay != default_initial_delay() {
+            self.initial_delay = other.initial_delay;
+        }
+
+        if other.max_delay != default_max_delay() {
+            self.max_delay = other.max_delay;
+        }
+    }
+}
+
+#[inline(always)]
+const fn default_max_attempts() -> u16 {
+    5
+}
+
+#[inline(always)]
+const fn default_initial_delay() -> u64 {
+    30
+}
+
+#[inline(always)]
+const fn default_max_delay() -> u64 {
+    3600
+}
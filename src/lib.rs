@@ -33,9 +33,12 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub mod config;
 pub mod logging;
+pub mod queue;
 pub mod service;
 pub mod templates;
 
+// the generated clients and servers return `tonic::Status` from every gRPC method
+#[allow(clippy::result_large_err)]
 pub(crate) mod protos {
     tonic::include_proto!("noelware.charted.emails");
 
@@ -43,6 +46,10 @@ pub(crate) mod protos {
 }
 
 pub use protos::{
+    dead_letters_server::{DeadLetters, DeadLettersServer},
     emails_server::{Emails, EmailsServer},
-    Error, PingRequest, PingResponse, SendEmailRequest, SendEmailResponse,
+    Attachment, CancelScheduledRequest, DeadLetter, DeadLetterRequest, DeliveryState, Error, GetStatusRequest,
+    GetStatusResponse, InlineAsset, ListDeadLettersRequest, ListDeadLettersResponse, ListTemplatesRequest,
+    ListTemplatesResponse, PingRequest, PingResponse, Recipient, RenderResponse, SendBatchRequest, SendBatchResponse,
+    SendEmailRequest, SendEmailResponse,
 };
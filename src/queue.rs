@@ -0,0 +1,650 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The delivery queue accepts emails that were already rendered and delivers them
+//! with a pool of background workers, so callers don't have to wait on the SMTP
+//! server to respond.
+
+use crate::config::queue::Config;
+use chrono::{DateTime, Utc};
+use eyre::Result;
+use lettre::{
+    address::Envelope, transport::smtp::response::Response, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use retry::Failure;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use storage::Storage;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, error, info, instrument, warn};
+use uuid::Uuid;
+
+pub mod retry;
+pub mod storage;
+
+/// How often messages that are past their retention are pruned.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Represents the state of a queued message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryState {
+    /// The message is waiting for a worker to pick it up.
+    Queued,
+
+    /// A worker is currently delivering the message.
+    Sending,
+
+    /// The SMTP server accepted the message.
+    Delivered,
+
+    /// Delivery failed, but the message will be tried again later.
+    Deferred,
+
+    /// Delivery failed and the message won't be tried again. The message is kept as
+    /// a dead letter until it is replayed or discarded.
+    Failed,
+
+    /// The message will be delivered at a later time.
+    Scheduled,
+
+    /// The message was scheduled, but was cancelled before it was delivered.
+    Cancelled,
+}
+
+impl DeliveryState {
+    /// Whether a message in this state still needs to be delivered.
+    pub fn is_pending(self) -> bool {
+        matches!(
+            self,
+            DeliveryState::Queued | DeliveryState::Sending | DeliveryState::Deferred | DeliveryState::Scheduled
+        )
+    }
+
+    /// Whether a message in this state won't be delivered again. Dead letters aren't done,
+    /// since they can be replayed.
+    pub fn is_done(self) -> bool {
+        matches!(self, DeliveryState::Delivered | DeliveryState::Cancelled)
+    }
+}
+
+/// Represents a message that was accepted into the [`Queue`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    /// Unique identifier of this message.
+    pub id: String,
+
+    /// Envelope to deliver the message with.
+    pub envelope: Envelope,
+
+    /// The formatted message that is sent to the SMTP server. This is kept
+    /// separately from the rest of the message by [`Storage`] implementations, and
+    /// is empty once the message is [done][DeliveryState::is_done].
+    #[serde(skip)]
+    pub raw: Vec<u8>,
+
+    /// Current delivery state.
+    pub state: DeliveryState,
+
+    /// Amount of times that delivery was attempted.
+    pub attempts: u32,
+
+    /// Last response (or error) from the SMTP server, if delivery was attempted.
+    pub last_response: Option<String>,
+
+    /// When the message was accepted into the queue.
+    pub queued_at: DateTime<Utc>,
+
+    /// When the state of this message last changed.
+    pub updated_at: DateTime<Utc>,
+
+    /// When delivery will be attempted next, if the message was deferred or scheduled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_attempt_at: Option<DateTime<Utc>>,
+}
+
+/// Represents the delivery queue, which keeps its messages in a [`Storage`].
+#[derive(Clone)]
+pub struct Queue {
+    storage: Arc<dyn Storage>,
+    sender: mpsc::UnboundedSender<String>,
+    receiver: Arc<Mutex<mpsc::UnboundedReceiver<String>>>,
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    config: Config,
+}
+
+impl Queue {
+    /// Creates a new [`Queue`] that keeps messages in `storage` and delivers them
+    /// with the given `mailer`.
+    pub fn new(storage: Arc<dyn Storage>, mailer: AsyncSmtpTransport<Tokio1Executor>, config: Config) -> Queue {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Queue {
+            receiver: Arc::new(Mutex::new(receiver)),
+            storage,
+            sender,
+            mailer,
+            config,
+        }
+    }
+
+    /// Reloads every pending message from the [`Storage`] and spawns background tasks
+    /// that deliver queued messages.
+    pub async fn start(&self) -> Result<()> {
+        self.storage.init().await?;
+
+        let pending = self.storage.pending().await?;
+        info!(
+            messages = pending.len(),
+            "reloaded pending messages into delivery queue"
+        );
+
+        for mut message in pending {
+            // the process exited while this message was being delivered, so we don't know
+            // if the SMTP server accepted it or not; it's better to deliver it twice than never.
+            if message.state == DeliveryState::Sending {
+                message.state = DeliveryState::Queued;
+                self.storage.put(&message).await?;
+            }
+
+            self.schedule(message.id, message.next_attempt_at);
+        }
+
+        let workers = self.config.workers.max(1);
+        info!(workers, "starting delivery queue workers");
+        for worker in 0..workers {
+            let queue = self.clone();
+            tokio::spawn(async move { queue.work(worker).await });
+        }
+
+        let queue = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = queue.prune().await {
+                    error!(error = %e, "unable to prune delivery queue");
+                    sentry::capture_error(&*e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Deletes delivered and cancelled messages that are older than `queue.retention`, and
+    /// dead letters that are older than `queue.dead_letter_retention`.
+    pub async fn prune(&self) -> Result<()> {
+        let now = Utc::now();
+        for (state, retention) in [
+            (DeliveryState::Delivered, self.config.retention),
+            (DeliveryState::Cancelled, self.config.retention),
+            (DeliveryState::Failed, self.config.dead_letter_retention),
+        ] {
+            let before = chrono::Duration::from_std(Duration::from_secs(retention))
+                .ok()
+                .and_then(|retention| now.checked_sub_signed(retention))
+                .unwrap_or(DateTime::<Utc>::MIN_UTC);
+            let pruned = self.storage.prune(state, before).await?;
+            if pruned > 0 {
+                debug!(?state, pruned, "pruned messages from delivery queue");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Accepts a [`Message`] into the queue and returns its ID. If `send_at` is in the future,
+    /// then the message won't be delivered until then.
+    pub async fn enqueue(&self, message: Message, send_at: Option<DateTime<Utc>>) -> Result<String> {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now();
+        let send_at = send_at.filter(|at| *at > now);
+        let queued = QueuedMessage {
+            id: id.clone(),
+            envelope: message.envelope().clone(),
+            raw: message.formatted(),
+            state: match send_at {
+                Some(_) => DeliveryState::Scheduled,
+                None => DeliveryState::Queued,
+            },
+            attempts: 0,
+            last_response: None,
+            queued_at: now,
+            updated_at: now,
+            next_attempt_at: send_at,
+        };
+
+        self.storage.put(&queued).await?;
+        self.schedule(id.clone(), send_at);
+        debug!(%id, ?send_at, "queued message for delivery");
+
+        Ok(id)
+    }
+
+    /// Cancels a scheduled message so it won't be delivered, returning `false` if it isn't
+    /// scheduled anymore (i.e, a worker has started to deliver it in the meantime).
+    pub async fn cancel(&self, mut message: QueuedMessage) -> Result<bool> {
+        info!(id = message.id, "cancelling scheduled message");
+
+        message.state = DeliveryState::Cancelled;
+        message.next_attempt_at = None;
+        message.updated_at = Utc::now();
+
+        self.storage.put_if(&message, DeliveryState::Scheduled).await
+    }
+
+    /// Returns a queued message by its ID.
+    pub async fn get(&self, id: &str) -> Result<Option<QueuedMessage>> {
+        self.storage.get(id).await
+    }
+
+    /// Returns every dead letter, which are messages that failed permanently or
+    /// ran out of delivery attempts. Their formatted messages aren't loaded, use [`Queue::get`]
+    /// for those.
+    pub async fn dead_letters(&self) -> Result<Vec<QueuedMessage>> {
+        self.storage.list(DeliveryState::Failed).await
+    }
+
+    /// Puts a dead letter back into the queue with a fresh set of delivery attempts.
+    pub async fn replay(&self, mut message: QueuedMessage) -> Result<()> {
+        info!(id = message.id, attempts = message.attempts, "replaying dead letter");
+
+        message.state = DeliveryState::Queued;
+        message.attempts = 0;
+        message.next_attempt_at = None;
+        message.updated_at = Utc::now();
+
+        self.storage.put(&message).await?;
+        self.schedule(message.id, None);
+
+        Ok(())
+    }
+
+    /// Discards a dead letter, returning `false` if it didn't exist.
+    pub async fn discard(&self, id: &str) -> Result<bool> {
+        info!(%id, "discarding dead letter");
+        self.storage.delete(id).await
+    }
+
+    /// Hands a message to the workers once `at` has passed, or right away if it's `None`.
+    fn schedule(&self, id: String, at: Option<DateTime<Utc>>) {
+        let delay = at.and_then(|at| (at - Utc::now()).to_std().ok());
+        let sender = self.sender.clone();
+
+        // the receiver is owned by the queue itself, so sending can't fail
+        match delay {
+            Some(delay) => {
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = sender.send(id);
+                });
+            }
+
+            None => {
+                let _ = sender.send(id);
+            }
+        }
+    }
+
+    async fn work(&self, worker: u16) {
+        loop {
+            let Some(id) = self.receiver.lock().await.recv().await else {
+                debug!(worker, "delivery queue was closed, stopping worker");
+                return;
+            };
+
+            if let Err(e) = self.deliver(&id).await {
+                error!(%id, error = %e, "unable to update queued message");
+                sentry::capture_error(&*e);
+            }
+        }
+    }
+
+    #[instrument(name = "emails.queue.deliver", skip(self))]
+    async fn deliver(&self, id: &str) -> Result<()> {
+        let Some(mut message) = self.storage.get(id).await? else {
+            warn!("queued message no longer exists, skipping");
+            return Ok(());
+        };
+
+        // scheduled messages are still handed to the workers after they've been
+        // cancelled, or they could've been replayed or discarded in the meantime.
+        if !matches!(
+            message.state,
+            DeliveryState::Queued | DeliveryState::Deferred | DeliveryState::Scheduled
+        ) {
+            debug!(state = ?message.state, "queued message is no longer waiting for delivery, skipping");
+            return Ok(());
+        }
+
+        let waiting = message.state;
+        message.state = DeliveryState::Sending;
+        message.updated_at = Utc::now();
+        message.next_attempt_at = None;
+        if !self.storage.put_if(&message, waiting).await? {
+            debug!("queued message was changed by someone else, skipping");
+            return Ok(());
+        }
+
+        let raw = with_date(&message.raw, Utc::now());
+        let result = self.mailer.send_raw(&message.envelope, &raw).await;
+        message.attempts += 1;
+        message.updated_at = Utc::now();
+
+        match result {
+            Ok(response) => {
+                debug!("delivered message");
+
+                message.state = DeliveryState::Delivered;
+                message.last_response = Some(format_response(&response));
+            }
+
+            Err(e) => {
+                message.last_response = Some(e.to_string());
+
+                let attempts_left = message.attempts < u32::from(self.config.retry.max_attempts);
+                match Failure::classify(&e) {
+                    Failure::Transient if attempts_left => {
+                        let delay = retry::backoff(&self.config.retry, message.attempts);
+                        warn!(error = %e, attempts = message.attempts, ?delay, "unable to deliver message, retrying later");
+
+                        message.state = DeliveryState::Deferred;
+                        message.next_attempt_at = Some(message.updated_at + delay);
+                    }
+
+                    failure => {
+                        error!(error = %e, attempts = message.attempts, ?failure, "unable to deliver message");
+                        sentry::capture_error(&e);
+
+                        message.state = DeliveryState::Failed;
+                    }
+                }
+            }
+        }
+
+        self.storage.put(&message).await?;
+        if message.state == DeliveryState::Deferred {
+            self.schedule(message.id, message.next_attempt_at);
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces the `Date` header of a formatted message with `date`. Messages are formatted when
+/// they are queued, but might be delivered a lot later if they were scheduled or deferred.
+fn with_date(raw: &[u8], date: DateTime<Utc>) -> Vec<u8> {
+    let headers = raw
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(raw.len(), |end| end + 2);
+
+    let mut output = Vec::with_capacity(raw.len());
+    let mut replacing = false;
+    for line in raw[..headers].split_inclusive(|b| *b == b'\n') {
+        // folded lines continue the header before them
+        if replacing && matches!(line.first(), Some(b' ' | b'\t')) {
+            continue;
+        }
+
+        replacing = line.len() >= 5 && line[..5].eq_ignore_ascii_case(b"date:");
+        match replacing {
+            true => output.extend_from_slice(format!("Date: {}\r\n", date.to_rfc2822()).as_bytes()),
+            false => output.extend_from_slice(line),
+        }
+    }
+
+    output.extend_from_slice(&raw[headers..]);
+    output
+}
+
+/// Formats a [`Response`] from the SMTP server, i.e. `250 2.0.0 Ok: queued`.
+fn format_response(response: &Response) -> String {
+    let message = response.message().collect::<Vec<_>>().join(" ");
+    format!("{} {message}", response.code())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::queue::RetryConfig;
+    use chrono::Duration as TimeDelta;
+    use std::{collections::VecDeque, sync::Mutex as StdMutex};
+    use storage::memory::MemoryStorage;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        time::{sleep, timeout},
+    };
+
+    /// Starts a SMTP server that replies to each message with the next reply in `replies`,
+    /// and accepts messages once they ran out.
+    async fn smtp_server(replies: Vec<&'static str>) -> AsyncSmtpTransport<Tokio1Executor> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let replies = Arc::new(StdMutex::new(VecDeque::from(replies)));
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let replies = replies.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut lines = BufReader::new(read).lines();
+                    let mut data = false;
+
+                    write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let reply = match (data, line.to_ascii_uppercase().as_str()) {
+                            (true, ".") => {
+                                data = false;
+                                replies.lock().unwrap().pop_front().unwrap_or("250 2.0.0 Ok: queued")
+                            }
+
+                            (true, _) => continue,
+                            (false, "DATA") => {
+                                data = true;
+                                "354 End data with <CR><LF>.<CR><LF>"
+                            }
+
+                            (false, "QUIT") => "221 Bye",
+                            (false, _) => "250 localhost",
+                        };
+
+                        if write.write_all(format!("{reply}\r\n").as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1")
+            .port(port)
+            .build()
+    }
+
+    fn config() -> Config {
+        Config {
+            workers: 1,
+            retry: RetryConfig {
+                max_attempts: 2,
+                initial_delay: 1,
+                max_delay: 1,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn message() -> Message {
+        Message::builder()
+            .from("charted <noreply@charted.dev>".parse().unwrap())
+            .to("noel@example.com".parse().unwrap())
+            .subject("Hello")
+            .body(String::from("world"))
+            .unwrap()
+    }
+
+    /// Waits until the message with `id` is in `state`.
+    async fn wait_for(queue: &Queue, id: &str, state: DeliveryState) -> QueuedMessage {
+        timeout(std::time::Duration::from_secs(10), async {
+            loop {
+                match queue.get(id).await.unwrap() {
+                    Some(message) if message.state == state => return message,
+                    _ => sleep(std::time::Duration::from_millis(20)).await,
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("message {id} never became {state:?}"))
+    }
+
+    #[tokio::test]
+    async fn deliver_queued_messages() {
+        let queue = Queue::new(Arc::new(MemoryStorage::default()), smtp_server(vec![]).await, config());
+        queue.start().await.unwrap();
+
+        let id = queue.enqueue(message(), None).await.unwrap();
+        let delivered = wait_for(&queue, &id, DeliveryState::Delivered).await;
+        assert_eq!(delivered.attempts, 1);
+        assert_eq!(delivered.last_response.as_deref(), Some("250 2.0.0 Ok: queued"));
+        assert!(delivered.raw.is_empty());
+
+        // delivered messages are kept until they are past their retention
+        queue.prune().await.unwrap();
+        assert!(queue.get(&id).await.unwrap().is_some());
+
+        let queue = Queue {
+            config: Config {
+                retention: 0,
+                ..config()
+            },
+            ..queue
+        };
+
+        queue.prune().await.unwrap();
+        assert!(queue.get(&id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn retry_transient_failures() {
+        let mailer = smtp_server(vec![
+            "451 4.3.0 Try again later",
+            "550 5.1.1 No such user",
+            "451 4.3.0 Try again later",
+            "451 4.3.0 Try again later",
+        ])
+        .await;
+
+        let queue = Queue::new(Arc::new(MemoryStorage::default()), mailer, config());
+        queue.start().await.unwrap();
+
+        let id = queue.enqueue(message(), None).await.unwrap();
+        let deferred = wait_for(&queue, &id, DeliveryState::Deferred).await;
+        assert_eq!(deferred.attempts, 1);
+        assert!(deferred.next_attempt_at.is_some());
+
+        // the retry is rejected permanently, so it isn't tried again
+        let failed = wait_for(&queue, &id, DeliveryState::Failed).await;
+        assert_eq!(failed.attempts, 2);
+        assert!(failed.last_response.as_deref().unwrap().contains("No such user"));
+
+        // replayed dead letters get a fresh set of attempts, which both fail
+        queue.replay(failed).await.unwrap();
+        wait_for(&queue, &id, DeliveryState::Deferred).await;
+        let failed = wait_for(&queue, &id, DeliveryState::Failed).await;
+        assert_eq!(failed.attempts, 2);
+        assert!(!failed.raw.is_empty());
+        assert_eq!(queue.dead_letters().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancel_scheduled_messages() {
+        let queue = Queue::new(Arc::new(MemoryStorage::default()), smtp_server(vec![]).await, config());
+        queue.start().await.unwrap();
+
+        let id = queue
+            .enqueue(message(), Some(Utc::now() + TimeDelta::hours(1)))
+            .await
+            .unwrap();
+
+        let scheduled = wait_for(&queue, &id, DeliveryState::Scheduled).await;
+        assert!(queue.cancel(scheduled.clone()).await.unwrap());
+
+        let cancelled = wait_for(&queue, &id, DeliveryState::Cancelled).await;
+        assert_eq!(cancelled.attempts, 0);
+        assert!(cancelled.raw.is_empty());
+
+        // a worker picking up the stale copy can't deliver it anymore, and messages that
+        // aren't scheduled anymore can't be cancelled
+        queue.deliver(&id).await.unwrap();
+        assert_eq!(queue.get(&id).await.unwrap().unwrap().state, DeliveryState::Cancelled);
+        assert!(!queue.cancel(scheduled).await.unwrap());
+    }
+
+    #[test]
+    fn date_is_set_when_delivered() {
+        let raw = message().formatted();
+        let date = Utc::now() + TimeDelta::days(1);
+        let stamped = String::from_utf8(with_date(&raw, date)).unwrap();
+
+        let dates = stamped
+            .lines()
+            .filter(|line| line.starts_with("Date:"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(dates, vec![format!("Date: {}", date.to_rfc2822())]);
+        assert!(stamped.contains("Subject: Hello\r\n"));
+        assert!(stamped.ends_with("\r\n\r\nworld"));
+    }
+
+    #[tokio::test]
+    async fn reload_pending_messages_on_restart() {
+        let storage = Arc::new(MemoryStorage::default());
+        let message = message();
+        let now = Utc::now();
+        for state in [DeliveryState::Sending, DeliveryState::Deferred] {
+            storage
+                .put(&QueuedMessage {
+                    id: Uuid::new_v4().to_string(),
+                    envelope: message.envelope().clone(),
+                    raw: message.formatted(),
+                    state,
+                    attempts: 1,
+                    last_response: None,
+                    queued_at: now,
+                    updated_at: now,
+                    next_attempt_at: (state == DeliveryState::Deferred).then_some(now),
+                })
+                .await
+                .unwrap();
+        }
+
+        // messages that were being sent when the process exited are delivered again
+        let queue = Queue::new(storage.clone(), smtp_server(vec![]).await, config());
+        queue.start().await.unwrap();
+
+        timeout(std::time::Duration::from_secs(10), async {
+            while !storage.pending().await.unwrap().is_empty() {
+                sleep(std::time::Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("pending messages were never delivered");
+
+        let delivered = storage.list(DeliveryState::Delivered).await.unwrap();
+        assert_eq!(delivered.len(), 2);
+        assert!(delivered.iter().all(|message| message.attempts == 2));
+    }
+}
@@ -0,0 +1,83 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::queue::RetryConfig;
+use lettre::transport::smtp;
+use rand::Rng;
+use std::time::Duration;
+
+/// Represents how a delivery failure should be handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The failure might not happen again (i.e, a 4xx reply or the connection was reset),
+    /// so delivery can be tried again later.
+    Transient,
+
+    /// The failure will happen again (i.e, a 5xx reply), so retrying won't help.
+    Permanent,
+}
+
+impl Failure {
+    /// Sorts an error from the SMTP transport into a [`Failure`].
+    pub fn classify(error: &smtp::Error) -> Failure {
+        // 4xx replies, timeouts and connection/network errors are the only ones
+        // that can resolve themselves.
+        if error.is_permanent() || error.is_client() || error.is_response() || error.is_tls() {
+            return Failure::Permanent;
+        }
+
+        Failure::Transient
+    }
+}
+
+/// Returns how long to wait before delivery attempt `attempt + 1`, after `attempt` attempts
+/// have failed. The delay doubles with each attempt and a random jitter of up to half of
+/// the delay is taken off, so that retries from a single outage are spread out.
+pub fn backoff(config: &RetryConfig, attempt: u32) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(32);
+    let delay = config
+        .initial_delay
+        .saturating_mul(1u64 << exponent)
+        .min(config.max_delay)
+        .max(1);
+
+    let jitter = rand::thread_rng().gen_range(0..=delay / 2);
+    Duration::from_secs(delay - jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::backoff;
+    use crate::config::queue::RetryConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay() {
+        let config = RetryConfig {
+            max_attempts: 10,
+            initial_delay: 10,
+            max_delay: 60,
+        };
+
+        for (attempt, expected) in [(1, 10), (2, 20), (3, 40), (4, 60), (9, 60)] {
+            let delay = backoff(&config, attempt);
+            assert!(delay <= Duration::from_secs(expected), "attempt {attempt}: {delay:?}");
+            assert!(
+                delay >= Duration::from_secs(expected / 2),
+                "attempt {attempt}: {delay:?}"
+            );
+        }
+    }
+}
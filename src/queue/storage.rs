@@ -0,0 +1,68 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{DeliveryState, QueuedMessage};
+use chrono::{DateTime, Utc};
+use eyre::Result;
+
+pub mod filesystem;
+pub mod memory;
+
+/// Represents a trait that allows the [`Queue`][super::Queue] to store messages, so
+/// that they can be delivered (or looked up) later.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Allows this [`Storage`] to do pre-initialization (i.e, creating the data directory).
+    async fn init(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Inserts or replaces a message. The formatted message of a message that is
+    /// [done][DeliveryState::is_done] doesn't need to be kept.
+    async fn put(&self, message: &QueuedMessage) -> Result<()>;
+
+    /// Replaces a message only if it's still in the `expected` state, returning `false` if it
+    /// isn't (or doesn't exist anymore). This is atomic with other calls to `put_if`, so only
+    /// one of two concurrent state changes (i.e, cancelling a scheduled message while it's
+    /// being delivered) can win.
+    async fn put_if(&self, message: &QueuedMessage, expected: DeliveryState) -> Result<bool>;
+
+    /// Returns a message by its ID, if it exists.
+    async fn get(&self, id: &str) -> Result<Option<QueuedMessage>>;
+
+    /// Returns every message that wasn't delivered yet and will be tried again, which
+    /// are reloaded into the queue when the service starts.
+    async fn pending(&self) -> Result<Vec<QueuedMessage>>;
+
+    /// Returns every message that is in the given `state`, without their formatted message
+    /// (`raw` is empty), which can be loaded with [`get`][Storage::get] when it's needed.
+    async fn list(&self, state: DeliveryState) -> Result<Vec<QueuedMessage>>;
+
+    /// Deletes a message, returning `false` if it didn't exist.
+    async fn delete(&self, id: &str) -> Result<bool>;
+
+    /// Deletes every message in `state` that was last updated before `before`, returning
+    /// how many messages were deleted.
+    async fn prune(&self, state: DeliveryState, before: DateTime<Utc>) -> Result<usize> {
+        let mut pruned = 0;
+        for message in self.list(state).await? {
+            if message.updated_at < before && self.delete(&message.id).await? {
+                pruned += 1;
+            }
+        }
+
+        Ok(pruned)
+    }
+}
@@ -0,0 +1,296 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::Storage;
+use crate::queue::{DeliveryState, QueuedMessage};
+use chrono::{DateTime, Utc};
+use eyre::{Context, Result};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{fs, sync::Mutex};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Represents a [`Storage`] that keeps messages in a directory on the local filesystem, so
+/// they survive restarts. Each message is kept as two files: `{id}.json` for its metadata
+/// and `{id}.eml` for the formatted message itself, which is removed once the message is
+/// [done][DeliveryState::is_done].
+#[derive(Debug, Clone)]
+pub struct FilesystemStorage {
+    directory: PathBuf,
+
+    // held while a message is compared and replaced in `put_if`
+    lock: Arc<Mutex<()>>,
+}
+
+impl FilesystemStorage {
+    /// Creates a new [`FilesystemStorage`] instance.
+    pub fn new<P: AsRef<Path>>(directory: P) -> FilesystemStorage {
+        FilesystemStorage {
+            directory: directory.as_ref().into(),
+            lock: Arc::default(),
+        }
+    }
+
+    /// Returns the path of a message's file with the given `extension`, or `None` if
+    /// `id` isn't a valid message ID. IDs are sent by clients, so this makes sure they
+    /// can't point outside of the directory.
+    fn path(&self, id: &str, extension: &str) -> Option<PathBuf> {
+        Uuid::parse_str(id)
+            .ok()
+            .map(|id| self.directory.join(format!("{id}.{extension}")))
+    }
+
+    async fn read_metadata(&self, metadata: &Path) -> Result<QueuedMessage> {
+        let contents = fs::read(metadata).await?;
+        serde_json::from_slice(&contents).map_err(Into::into)
+    }
+
+    async fn read(&self, metadata: &Path) -> Result<QueuedMessage> {
+        let mut message = self.read_metadata(metadata).await?;
+        if !message.state.is_done() {
+            message.raw = fs::read(metadata.with_extension("eml")).await?;
+        }
+
+        Ok(message)
+    }
+
+    /// Reads the metadata of every message in the directory that matches `filter`,
+    /// returning them with the path of their metadata.
+    async fn scan<F: Fn(&QueuedMessage) -> bool>(&self, filter: F) -> Result<Vec<(PathBuf, QueuedMessage)>> {
+        let mut messages = vec![];
+        let mut entries = fs::read_dir(&self.directory).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+
+            match self.read_metadata(&path).await {
+                Ok(message) if filter(&message) => messages.push((path, message)),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(path = %path.display(), error = %e, "unable to read queued message, skipping");
+                }
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// Reads every message in the directory that matches `filter`, along with the
+    /// formatted message.
+    async fn read_all<F: Fn(&QueuedMessage) -> bool>(&self, filter: F) -> Result<Vec<QueuedMessage>> {
+        let mut messages = vec![];
+        for (path, _) in self.scan(filter).await? {
+            match self.read(&path).await {
+                Ok(message) => messages.push(message),
+                Err(e) => {
+                    warn!(path = %path.display(), error = %e, "unable to read queued message, skipping");
+                }
+            }
+        }
+
+        Ok(messages)
+    }
+}
+
+#[async_trait]
+impl Storage for FilesystemStorage {
+    async fn init(&self) -> Result<()> {
+        if !self.directory.try_exists()? {
+            warn!(
+                directory = tracing::field::display(self.directory.display()),
+                "queue data directory doesn't exist, creating"
+            );
+
+            fs::create_dir_all(&self.directory).await?;
+        }
+
+        info!(
+            directory = tracing::field::display(self.directory.display()),
+            "using filesystem for queue storage"
+        );
+
+        Ok(())
+    }
+
+    async fn put(&self, message: &QueuedMessage) -> Result<()> {
+        let (Some(metadata), Some(raw)) = (self.path(&message.id, "json"), self.path(&message.id, "eml")) else {
+            return Err(eyre!("invalid message id '{}'", message.id));
+        };
+
+        // the formatted message never changes, so it only needs to be written once, and
+        // isn't needed anymore when the message won't be delivered again
+        if message.state.is_done() {
+            if raw.try_exists()? {
+                fs::remove_file(&raw).await?;
+            }
+        } else if !raw.try_exists()? {
+            fs::write(&raw, &message.raw).await?;
+        }
+
+        // write to a temporary file first, so a crash while writing doesn't leave
+        // a corrupted message behind.
+        let tmp = metadata.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(message)?).await?;
+        fs::rename(&tmp, &metadata)
+            .await
+            .with_context(|| format!("unable to persist message {}", message.id))
+    }
+
+    async fn put_if(&self, message: &QueuedMessage, expected: DeliveryState) -> Result<bool> {
+        let Some(metadata) = self.path(&message.id, "json") else {
+            return Ok(false);
+        };
+
+        let _guard = self.lock.lock().await;
+        if !metadata.try_exists()? || self.read_metadata(&metadata).await?.state != expected {
+            return Ok(false);
+        }
+
+        self.put(message).await.map(|_| true)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<QueuedMessage>> {
+        let Some(metadata) = self.path(id, "json") else {
+            return Ok(None);
+        };
+
+        if !metadata.try_exists()? {
+            return Ok(None);
+        }
+
+        self.read(&metadata).await.map(Some)
+    }
+
+    async fn pending(&self) -> Result<Vec<QueuedMessage>> {
+        self.read_all(|message| message.state.is_pending()).await
+    }
+
+    async fn list(&self, state: DeliveryState) -> Result<Vec<QueuedMessage>> {
+        let messages = self.scan(|message| message.state == state).await?;
+        Ok(messages.into_iter().map(|(_, message)| message).collect())
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool> {
+        let (Some(metadata), Some(raw)) = (self.path(id, "json"), self.path(id, "eml")) else {
+            return Ok(false);
+        };
+
+        if !metadata.try_exists()? {
+            return Ok(false);
+        }
+
+        fs::remove_file(metadata).await?;
+        if raw.try_exists()? {
+            fs::remove_file(raw).await?;
+        }
+
+        Ok(true)
+    }
+
+    async fn prune(&self, state: DeliveryState, before: DateTime<Utc>) -> Result<usize> {
+        let mut pruned = 0;
+        for (_, message) in self
+            .scan(|message| message.state == state && message.updated_at < before)
+            .await?
+        {
+            if self.delete(&message.id).await? {
+                pruned += 1;
+            }
+        }
+
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FilesystemStorage;
+    use crate::queue::{storage::Storage, DeliveryState, QueuedMessage};
+    use chrono::Utc;
+    use lettre::address::Envelope;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn put_and_reload_pending_messages() {
+        let directory = std::env::temp_dir().join(format!("charted-emails-{}", Uuid::new_v4()));
+        let storage = FilesystemStorage::new(&directory);
+        storage.init().await.unwrap();
+
+        let mut message = QueuedMessage {
+            id: Uuid::new_v4().to_string(),
+            envelope: Envelope::new(None, vec!["noel@example.com".parse().unwrap()]).unwrap(),
+            raw: b"Subject: hello\r\n\r\nworld".to_vec(),
+            state: DeliveryState::Queued,
+            attempts: 0,
+            last_response: None,
+            queued_at: Utc::now(),
+            updated_at: Utc::now(),
+            next_attempt_at: None,
+        };
+
+        storage.put(&message).await.unwrap();
+
+        let pending = storage.pending().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].raw, message.raw);
+
+        message.state = DeliveryState::Delivered;
+        storage.put(&message).await.unwrap();
+
+        assert!(storage.pending().await.unwrap().is_empty());
+        assert!(!directory.join(format!("{}.eml", message.id)).exists());
+        assert_eq!(
+            storage.get(&message.id).await.unwrap().map(|m| m.state),
+            Some(DeliveryState::Delivered)
+        );
+
+        assert_eq!(
+            storage
+                .prune(DeliveryState::Delivered, message.updated_at)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(storage.prune(DeliveryState::Delivered, Utc::now()).await.unwrap(), 1);
+        assert!(storage.get(&message.id).await.unwrap().is_none());
+
+        let mut failed = QueuedMessage {
+            id: Uuid::new_v4().to_string(),
+            state: DeliveryState::Failed,
+            raw: b"Subject: failed\r\n\r\nworld".to_vec(),
+            ..message.clone()
+        };
+
+        storage.put(&failed).await.unwrap();
+
+        // listing only reads the metadata, the formatted message is only read by `get`
+        let listed = storage.list(DeliveryState::Failed).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(listed[0].raw.is_empty());
+        assert_eq!(
+            storage.get(&failed.id).await.unwrap().map(|m| m.raw),
+            Some(std::mem::take(&mut failed.raw))
+        );
+
+        assert!(storage.get("../../etc/passwd").await.unwrap().is_none());
+        tokio::fs::remove_dir_all(directory).await.unwrap();
+    }
+}
@@ -0,0 +1,103 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::Storage;
+use crate::queue::{DeliveryState, QueuedMessage};
+use chrono::{DateTime, Utc};
+use eyre::Result;
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+/// Represents a [`Storage`] that keeps messages in memory, which are lost once
+/// the process exits.
+#[derive(Debug, Default)]
+pub struct MemoryStorage(RwLock<HashMap<String, QueuedMessage>>);
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, message: &QueuedMessage) -> Result<()> {
+        self.0.write().await.insert(message.id.clone(), stored(message));
+        Ok(())
+    }
+
+    async fn put_if(&self, message: &QueuedMessage, expected: DeliveryState) -> Result<bool> {
+        let mut messages = self.0.write().await;
+        if messages.get(&message.id).map(|stored| stored.state) != Some(expected) {
+            return Ok(false);
+        }
+
+        messages.insert(message.id.clone(), stored(message));
+        Ok(true)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<QueuedMessage>> {
+        Ok(self.0.read().await.get(id).cloned())
+    }
+
+    async fn pending(&self) -> Result<Vec<QueuedMessage>> {
+        Ok(self
+            .0
+            .read()
+            .await
+            .values()
+            .filter(|message| message.state.is_pending())
+            .cloned()
+            .collect())
+    }
+
+    async fn list(&self, state: DeliveryState) -> Result<Vec<QueuedMessage>> {
+        Ok(self
+            .0
+            .read()
+            .await
+            .values()
+            .filter(|message| message.state == state)
+            .map(|message| QueuedMessage {
+                id: message.id.clone(),
+                envelope: message.envelope.clone(),
+                raw: Vec::new(),
+                state: message.state,
+                attempts: message.attempts,
+                last_response: message.last_response.clone(),
+                queued_at: message.queued_at,
+                updated_at: message.updated_at,
+                next_attempt_at: message.next_attempt_at,
+            })
+            .collect())
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool> {
+        Ok(self.0.write().await.remove(id).is_some())
+    }
+
+    async fn prune(&self, state: DeliveryState, before: DateTime<Utc>) -> Result<usize> {
+        let mut messages = self.0.write().await;
+        let len = messages.len();
+        messages.retain(|_, message| message.state != state || message.updated_at >= before);
+
+        Ok(len - messages.len())
+    }
+}
+
+/// Returns the copy of `message` that is kept, which doesn't need the formatted message
+/// once it's done.
+fn stored(message: &QueuedMessage) -> QueuedMessage {
+    let mut message = message.clone();
+    if message.state.is_done() {
+        message.raw = Vec::new();
+    }
+
+    message
+}
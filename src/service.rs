@@ -16,44 +16,73 @@
 use crate::{
     config::Config,
     protos,
+    queue::{
+        self,
+        retry::{self, Failure},
+        storage::{filesystem::FilesystemStorage, memory::MemoryStorage, Storage},
+        Queue,
+    },
     templates::{
         self,
-        resolver::{filesystem::FilesystemTemplateResolver, TemplateResolver},
+        cache::TemplateCache,
+        compiled::{Body, CompiledTemplate},
+        front_matter::FrontMatter,
+        resolver::{
+            composite::CompositeTemplateResolver, email_template::EmailTemplateResolver,
+            filesystem::FilesystemTemplateResolver, git::GitTemplateResolver, kubernetes::KubernetesTemplateResolver,
+            s3::S3TemplateResolver, TemplateResolver,
+        },
     },
-    Emails, EmailsServer, Error, PingRequest, PingResponse, SendEmailRequest, SendEmailResponse, COMMIT_HASH, VERSION,
+    CancelScheduledRequest, DeadLettersServer, DeliveryState, Emails, EmailsServer, Error, GetStatusRequest,
+    GetStatusResponse, ListTemplatesRequest, ListTemplatesResponse, PingRequest, PingResponse, Recipient,
+    RenderResponse, SendBatchRequest, SendBatchResponse, SendEmailRequest, SendEmailResponse, COMMIT_HASH, VERSION,
 };
+use chrono::{DateTime, Utc};
+use dead_letters::DeadLetterService;
 use eyre::{Context, Result};
+use futures::{future::BoxFuture, stream, StreamExt};
 use lettre::{
-    message::Mailbox, transport::smtp::authentication::Credentials, Address, AsyncSmtpTransport, AsyncTransport,
-    Message, Tokio1Executor,
+    message::{header::ContentType, Attachment, Mailbox, SinglePart},
+    transport::smtp::authentication::Credentials,
+    Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
 };
-use mustache::Data;
-use prost_types::{value::Kind, ListValue};
+use mime::MimeBody;
+use prost_types::{value::Kind, ListValue, Struct, Timestamp, Value};
 use sentry::{types::Dsn, ClientInitGuard};
 use sentry_tower::NewSentryLayer;
-use std::{borrow::Cow, collections::HashMap, path::PathBuf, str::FromStr};
-use tonic::{transport::Server, Request, Response, Status};
-use tonic_health::server::health_reporter;
+use std::{borrow::Cow, collections::HashMap, path::PathBuf, str::FromStr, sync::Arc};
+use tonic::{transport::Server, Code, Request, Response, Status};
+use tonic_health::{server::health_reporter, ServingStatus};
 use tracing::{debug, error, info, trace, warn};
 
+mod dead_letters;
+mod mime;
+
+/// Default maximum size of a decoded gRPC message in tonic.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Name that the health of the template resolver is reported as, which is not serving if the
+/// templates are stale (i.e, the latest commit of a Git repository couldn't be verified).
+const TEMPLATES_HEALTH_SERVICE: &str = "noelware.charted.emails.templates";
+
 /// Represents an implementation of the `charted-emails` gRPC server.
 pub struct Service {
     _sentry_guard: Option<ClientInitGuard>,
-    resolver: Box<dyn TemplateResolver>,
+    templates: Arc<TemplateCache>,
     config: Config,
     mailer: AsyncSmtpTransport<Tokio1Executor>,
+    queue: Queue,
 }
 
 impl Service {
     /// Creates a new [`Service`] instance.
     pub async fn new(config: Config) -> Result<Service> {
-        let resolver: Box<dyn TemplateResolver> = match config.templates {
-            templates::Config::Filesystem(ref cfg) => Box::new(FilesystemTemplateResolver::new(cfg.clone())),
-            _ => unimplemented!(),
-        };
-
+        let resolver = template_resolver(&config.templates).await?;
         resolver.init().await?;
 
+        let templates = Arc::new(TemplateCache::new(resolver, &config.cache));
+        templates.watch();
+
         let mut mailer = match config.smtp.starttls {
             true => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp.host)?,
             false => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp.host)?,
@@ -98,7 +127,8 @@ impl Service {
                     ..Default::default()
                 })
             }),
-            resolver,
+            queue: Queue::new(queue_storage(&config), mailer.clone(), config.queue.clone()),
+            templates,
             config,
             mailer,
         })
@@ -107,9 +137,28 @@ impl Service {
     /// Starts the gRPC server until a cancellation (CTRL+C) occurs or when
     /// the server unexpectely receives a shutdown signal.
     pub async fn start(self) -> Result<()> {
+        self.queue.start().await?;
+
         let (mut reporter, service) = health_reporter();
         info!("successfully created the healthcheck reporter");
         reporter.set_serving::<EmailsServer<Service>>().await;
+        reporter.set_serving::<DeadLettersServer<DeadLetterService>>().await;
+        if let Some(mut healthy) = self.templates.resolver().healthy() {
+            let mut reporter = reporter.clone();
+            tokio::spawn(async move {
+                loop {
+                    let status = match *healthy.borrow_and_update() {
+                        true => ServingStatus::Serving,
+                        false => ServingStatus::NotServing,
+                    };
+
+                    reporter.set_service_status(TEMPLATES_HEALTH_SERVICE, status).await;
+                    if healthy.changed().await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
 
         info!("creating reflection server");
         let reflection = tonic_reflection::server::Builder::configure()
@@ -119,27 +168,187 @@ impl Service {
         let addr = self.config.server.addr();
         info!(%addr, "now listening on");
 
+        // allow requests to carry as many attachments as configured, on top of tonic's
+        // default limit for the rest of the message.
+        let max_message_size = usize::try_from(self.config.attachments.max_total_size)
+            .unwrap_or(usize::MAX)
+            .saturating_add(DEFAULT_MAX_MESSAGE_SIZE);
+
         Server::builder()
             .layer(NewSentryLayer::new_from_top())
             .add_service(service)
             .add_service(reflection)
-            .add_service(EmailsServer::new(self))
+            .add_service(DeadLettersServer::new(DeadLetterService::new(self.queue.clone())))
+            .add_service(EmailsServer::new(self).max_decoding_message_size(max_message_size))
             .serve(addr)
             .await
             .context("unable to run gRPC service")
     }
 }
 
-#[async_trait]
-impl Emails for Service {
-    async fn ping(&self, _: Request<PingRequest>) -> Result<Response<PingResponse>, Status> {
-        Ok(Response::new(PingResponse { pong: true }))
+/// Creates the [`TemplateResolver`] for the configured templates. This returns a boxed future,
+/// since chains of resolvers are created recursively.
+fn template_resolver(config: &templates::Config) -> BoxFuture<'_, Result<Box<dyn TemplateResolver>>> {
+    Box::pin(async move {
+        let resolver: Box<dyn TemplateResolver> = match config {
+            templates::Config::Filesystem(cfg) => Box::new(FilesystemTemplateResolver::new(cfg.clone())),
+            templates::Config::Git(cfg) => Box::new(GitTemplateResolver::new((**cfg).clone())),
+            templates::Config::EmailTemplates(cfg) => Box::new(EmailTemplateResolver::new(cfg.clone()).await?),
+            templates::Config::Kubernetes(cfg) => Box::new(KubernetesTemplateResolver::new(cfg.clone()).await?),
+            templates::Config::S3(cfg) => Box::new(S3TemplateResolver::new((**cfg).clone())),
+            templates::Config::Chain(configs) => {
+                let mut resolvers = Vec::with_capacity(configs.len());
+                for config in configs {
+                    resolvers.push(template_resolver(config).await?);
+                }
+
+                Box::new(CompositeTemplateResolver::new(resolvers))
+            }
+        };
+
+        Ok(resolver)
+    })
+}
+
+/// Creates the [`Storage`] that the delivery queue keeps its messages in.
+fn queue_storage(config: &Config) -> Arc<dyn Storage> {
+    match config.queue.data_dir {
+        Some(ref directory) => Arc::new(FilesystemStorage::new(directory)),
+        None => {
+            warn!("'config.queue.data_dir' is not set, queued messages will be lost when the service restarts!");
+            Arc::new(MemoryStorage::default())
+        }
     }
+}
 
-    async fn send(&self, request: Request<SendEmailRequest>) -> Result<Response<SendEmailResponse>, Status> {
-        let request = request.get_ref();
-        debug!(to = request.to, "sending email to address");
+/// Templates and inline assets that were pulled while handling a request. A [`SendBatchRequest`]
+/// shares one of these for every email in the batch, so each distinct template or asset is only
+/// pulled (and compiled) once. Failures are also kept, so a broken template is not pulled again
+/// for every email in a batch.
+#[derive(Default)]
+struct PullCache {
+    templates: HashMap<String, Result<Arc<CompiledTemplate>, Status>>,
+    assets: HashMap<String, Result<Vec<u8>, Status>>,
+}
+
+/// Attachments and inline assets that were sent in a [`SendEmailRequest`].
+struct Attachments {
+    attached: Vec<SinglePart>,
+    inline: Vec<(String, SinglePart)>,
+}
+
+/// Represents an email that was rendered from a [`SendEmailRequest`].
+struct Rendered {
+    message: Message,
+    body: Body,
+    subject: String,
+}
+
+/// Reasons why an email couldn't be rendered.
+enum Rejected {
+    /// The request itself was invalid, which is reported back in the response.
+    Invalid(Error),
+
+    /// Something went wrong on our end.
+    Status(Status),
+}
+
+impl From<Error> for Rejected {
+    fn from(error: Error) -> Self {
+        Rejected::Invalid(error)
+    }
+}
+
+impl From<Status> for Rejected {
+    fn from(status: Status) -> Self {
+        Rejected::Status(status)
+    }
+}
+
+impl Service {
+    // `tonic::Status` is the error type for every gRPC method, so it isn't boxed here either.
+    #[allow(clippy::result_large_err)]
+    async fn send_email(&self, request: &SendEmailRequest, cache: &mut PullCache) -> Result<SendEmailResponse, Status> {
+        match self.render_email(request, cache, false).await {
+            Ok(rendered) => self.deliver(request, rendered.message).await,
+            Err(Rejected::Invalid(error)) => Ok(failed(error)),
+            Err(Rejected::Status(status)) => Err(status),
+        }
+    }
+
+    /// Delivers an email from a batch, or returns the response of an email that couldn't be
+    /// rendered as-is.
+    async fn deliver_batched(
+        &self,
+        request: &SendEmailRequest,
+        message: Result<Message, SendEmailResponse>,
+    ) -> SendEmailResponse {
+        match message {
+            Ok(message) => self
+                .deliver(request, message)
+                .await
+                .unwrap_or_else(|status| failed(status_to_error(&status))),
+            Err(response) => response,
+        }
+    }
+
+    /// Queues a rendered [`Message`] if the request asked for it, or sends it right away.
+    #[allow(clippy::result_large_err)]
+    async fn deliver(&self, request: &SendEmailRequest, message: Message) -> Result<SendEmailResponse, Status> {
+        if request.queue || request.send_at.is_some() {
+            let send_at = match request.send_at.clone().map(parse_send_at).transpose() {
+                Ok(send_at) => send_at,
+                Err(error) => return Ok(failed(error)),
+            };
+
+            let id = self.queue.enqueue(message, send_at).await.map_err(|e| {
+                error!(error = %e, "unable to queue message");
+                sentry::capture_error(&*e);
+
+                Status::internal("Internal Server Error")
+            })?;
+
+            return Ok(SendEmailResponse {
+                success: true,
+                errors: vec![],
+                message_id: Some(id),
+            });
+        }
+
+        match self.mailer.send(message).await {
+            Ok(_) => Ok(SendEmailResponse {
+                success: true,
+                errors: vec![],
+                message_id: None,
+            }),
+
+            // only queued emails are retried by us, so the caller is told whether (and when)
+            // it can send this one again.
+            Err(e) => {
+                let should_retry = Failure::classify(&e) == Failure::Transient;
+                Ok(failed(Error {
+                    code: String::from("UNABLE_TO_SEND_EMAIL"),
+                    message: e.to_string(),
+                    should_retry,
+                    retry_after: should_retry
+                        .then(|| retry::backoff(&self.config.queue.retry, 1))
+                        .and_then(|delay| delay.try_into().ok()),
+                    ..Default::default()
+                }))
+            }
+        }
+    }
 
+    /// Validates and renders a [`SendEmailRequest`] into a [`Message`] without sending it. If
+    /// `preview` is true, then the request doesn't need any recipients and the email is addressed
+    /// to the sender instead.
+    #[allow(clippy::result_large_err)]
+    async fn render_email(
+        &self,
+        request: &SendEmailRequest,
+        cache: &mut PullCache,
+        preview: bool,
+    ) -> Result<Rendered, Rejected> {
         let from = self.config.smtp.from_addr.parse::<Address>().map_err(|e| {
             error!(addr = self.config.smtp.from_addr, error = %e, "unable to parse from address");
             sentry::capture_error(&e);
@@ -147,168 +356,822 @@ impl Emails for Service {
             Status::internal("Internal Server Error")
         })?;
 
-        let to = request.to.parse::<Address>().map_err(|e| {
-            error!(addr = request.to, error = %e, "unable to parse the 'request.to' address");
-            sentry::capture_error(&e);
+        let mut recipients = Recipients::try_from(request)?;
+        if recipients.is_empty() {
+            if !preview {
+                return Err(Rejected::Invalid(Error {
+                    code: String::from("MISSING_ARG"),
+                    message: String::from(
+                        "expected at least one recipient in 'request.to', 'request.recipients', 'request.cc' or 'request.bcc'",
+                    ),
+                    ..Default::default()
+                }));
+            }
 
-            Status::internal("Internal Server Error")
-        })?;
+            recipients.to.push(Mailbox::new(None, from.clone()));
+        }
 
-        if let Some(content) = request.content.clone() {
-            trace!(to = request.to, from = self.config.smtp.from_addr, "{content}");
-
-            let message = Message::builder()
-                .from(Mailbox::new(None, from.clone()))
-                .to(Mailbox::new(None, to.clone()))
-                .subject(&request.subject)
-                .date_now()
-                .user_agent(format!(
-                    "Noelware/charted-emails (+https://github.com/charted-dev/emails; v{VERSION}+{COMMIT_HASH}"
-                ))
-                .body(content)
-                .map_err(|e| {
-                    error!(?to, ?from, error = %e, "unable to create message");
-                    sentry::capture_error(&e);
-
-                    Status::internal(e.to_string())
-                })?;
+        let attachments = self.attachments(request)?;
+        debug!(
+            to = recipients.to.len(),
+            cc = recipients.cc.len(),
+            bcc = recipients.bcc.len(),
+            "rendering email for recipients"
+        );
 
-            match self.mailer.send(message).await {
-                Ok(_) => {
-                    return Ok(Response::new(SendEmailResponse {
-                        success: true,
-                        errors: vec![],
-                    }))
-                }
+        let (body, front_matter) = match (&request.content, &request.html_content, &request.text_content) {
+            (None, None, None) => {
+                let Some(ref template) = request.template else {
+                    return Err(Rejected::Invalid(Error {
+                        code: String::from("MISSING_ARG"),
+                        message: String::from("missing 'request.template' argument"),
+                        ..Default::default()
+                    }));
+                };
 
-                Err(e) => {
-                    return Ok(Response::new(SendEmailResponse {
-                        success: false,
-                        errors: vec![Error {
-                            code: String::from("UNABLE_TO_SEND_EMAIL"),
-                            message: e.to_string(),
-                            details: None,
-                        }],
-                    }))
+                debug!(?from, %template, "using template code");
+                let context = request
+                    .context
+                    .as_ref()
+                    .map(|data| prost_value_to_json(Kind::StructValue(data.clone())))
+                    .unwrap_or_else(|| serde_json::Value::Object(Default::default()));
+
+                let compiled = self.compile_template(template, cache).await?;
+                if let Err(errors) = compiled.validate(&context) {
+                    warn!(%template, errors = errors.len(), "context doesn't match the template's schema");
+                    let errors = errors
+                        .into_iter()
+                        .map(|(path, message)| Value {
+                            kind: Some(Kind::StructValue(details([
+                                ("path", Kind::StringValue(path)),
+                                ("message", Kind::StringValue(message)),
+                            ]))),
+                        })
+                        .collect();
+
+                    return Err(Rejected::Invalid(Error {
+                        code: String::from("INVALID_CONTEXT"),
+                        message: format!("context doesn't match the schema of template '{template}'"),
+                        details: Some(details([("errors", Kind::ListValue(ListValue { values: errors }))])),
+                        ..Default::default()
+                    }));
                 }
-            }
-        }
 
-        let Some(ref template) = request.template else {
-            return Ok(Response::new(SendEmailResponse {
-                success: false,
-                errors: vec![Error {
-                    code: String::from("MISSING_ARG"),
-                    message: String::from("missing 'request.template' argument"),
-                    details: None,
-                }],
-            }));
-        };
+                let body = compiled.render(&context).map_err(|e| {
+                    error!(%template, error = %e, "unable to render template");
+                    sentry::capture_error(&*e);
 
-        debug!(?to, ?from, %template, "using template code");
-        let Some(contents) = self.resolver.pull(PathBuf::from(template)).await.map_err(|e| {
-            error!(%template, error = %e, "unable to pull template");
-            sentry::capture_error(&*e);
+                    Status::internal(format!("unable to render template ({template})"))
+                })?;
 
-            Status::internal("Internal Server Error")
-        })?
-        else {
-            warn!(%template, "unknown template");
-            return Err(Status::invalid_argument(format!("unknown template '{template}'")));
-        };
+                (body, compiled.front_matter.clone())
+            }
 
-        let context = request
-            .context
-            .as_ref()
-            .map(|data| prost_value_to_data_type(Kind::StructValue(data.clone())))
-            .unwrap_or(Data::Map(HashMap::default()));
+            (content, html, text) => {
+                trace!(
+                    ?content,
+                    ?html,
+                    ?text,
+                    from = self.config.smtp.from_addr,
+                    "using content from request"
+                );
 
-        let compiled = mustache::compile_str(contents.as_str()).map_err(|e| {
-            error!(%template, error = %e, "unable to compile mustache template");
-            sentry::capture_error(&e);
+                let body = Body {
+                    html: html.clone(),
+                    text: text.clone().or_else(|| content.clone()),
+                    subject: None,
+                };
 
-            Status::internal("unable to compile mustache template ({template})")
-        })?;
+                (body, FrontMatter::default())
+            }
+        };
 
-        let message = Message::builder()
-            .from(Mailbox::new(None, from.clone()))
-            .to(Mailbox::new(None, to.clone()))
-            .subject(&request.subject)
+        // the request's subject takes precedence over the template's
+        let subject = match (request.subject.is_empty(), &body.subject) {
+            (true, Some(subject)) => subject.trim().to_owned(),
+            _ => request.subject.clone(),
+        };
+
+        // the template can send from a different address than the configured one
+        let sender = front_matter.from.unwrap_or_else(|| Mailbox::new(None, from.clone()));
+        let mut builder = Message::builder()
+            .from(sender)
+            .subject(&subject)
             .date_now()
             .user_agent(format!(
                 "Noelware/charted-emails (+https://github.com/charted-dev/emails; v{VERSION}+{COMMIT_HASH}"
-            ))
-            .body(compiled.render_data_to_string(&context).map_err(|e| {
-                error!(%template, error = %e, "unable to compile mustache template");
-                sentry::capture_error(&e);
+            ));
+
+        for mailbox in recipients.to {
+            builder = builder.to(mailbox);
+        }
+
+        for mailbox in recipients.cc {
+            builder = builder.cc(mailbox);
+        }
+
+        for mailbox in recipients.bcc {
+            builder = builder.bcc(mailbox);
+        }
+
+        if recipients.reply_to.is_empty() {
+            recipients.reply_to.extend(front_matter.reply_to);
+        }
+
+        for mailbox in recipients.reply_to {
+            builder = builder.reply_to(mailbox);
+        }
 
-                Status::internal("unable to compile mustache template ({template})")
-            })?)
+        // inline assets can only be referenced from a HTML part
+        if body.html.is_none() && !attachments.inline.is_empty() {
+            return Err(Rejected::Invalid(Error {
+                code: String::from("INVALID_ARG"),
+                message: String::from("'request.inline_assets' can only be used with HTML content"),
+                ..Default::default()
+            }));
+        }
+
+        let inline = self.inline_assets(&body, attachments.inline, cache).await?;
+        let message = MimeBody::new(body.clone(), inline, attachments.attached)
+            .build(builder)
             .map_err(|e| {
-                error!(?to, ?from, error = %e, "unable to create message");
+                error!(?from, error = %e, "unable to create message");
                 sentry::capture_error(&e);
 
                 Status::internal(e.to_string())
             })?;
 
-        match self.mailer.send(message).await {
-            Ok(_) => {
-                return Ok(Response::new(SendEmailResponse {
-                    success: true,
-                    errors: vec![],
-                }))
+        Ok(Rendered { message, body, subject })
+    }
+
+    /// Validates the attachments and inline assets of a request against the configured
+    /// size limits and maps them into MIME parts.
+    fn attachments(&self, request: &SendEmailRequest) -> Result<Attachments, Error> {
+        let limits = &self.config.attachments;
+        let mut total = 0u64;
+        let mut attachments = Attachments {
+            attached: Vec::with_capacity(request.attachments.len()),
+            inline: Vec::with_capacity(request.inline_assets.len()),
+        };
+
+        let files = request
+            .attachments
+            .iter()
+            .map(|file| (&file.filename, &file.content_type, &file.content, false));
+
+        let assets = request
+            .inline_assets
+            .iter()
+            .map(|asset| (&asset.content_id, &asset.content_type, &asset.content, true));
+
+        for (name, content_type, content, inline) in files.chain(assets) {
+            let size = content.len() as u64;
+            total += size;
+
+            if size > limits.max_size {
+                return Err(Error {
+                    code: String::from("ATTACHMENT_TOO_LARGE"),
+                    message: format!(
+                        "attachment '{name}' is {size} bytes, which is over the limit of {} bytes",
+                        limits.max_size
+                    ),
+                    details: Some(details([
+                        ("filename", Kind::StringValue(name.clone())),
+                        ("size", Kind::NumberValue(size as f64)),
+                        ("limit", Kind::NumberValue(limits.max_size as f64)),
+                    ])),
+                    ..Default::default()
+                });
+            }
+
+            if total > limits.max_total_size {
+                return Err(Error {
+                    code: String::from("ATTACHMENTS_TOO_LARGE"),
+                    message: format!(
+                        "all attachments together are over the limit of {} bytes",
+                        limits.max_total_size
+                    ),
+                    details: Some(details([
+                        ("size", Kind::NumberValue(total as f64)),
+                        ("limit", Kind::NumberValue(limits.max_total_size as f64)),
+                    ])),
+                    ..Default::default()
+                });
+            }
+
+            let parsed = ContentType::parse(content_type).map_err(|e| Error {
+                code: String::from("INVALID_ATTACHMENT"),
+                message: format!("attachment '{name}' has an invalid content type '{content_type}': {e}"),
+                ..Default::default()
+            })?;
+
+            match inline {
+                true => attachments.inline.push((
+                    name.clone(),
+                    Attachment::new_inline(name.clone()).body(content.clone(), parsed),
+                )),
+
+                false => attachments
+                    .attached
+                    .push(Attachment::new(name.clone()).body(content.clone(), parsed)),
+            }
+        }
+
+        Ok(attachments)
+    }
+
+    /// Resolves every `cid:` URL in the HTML part of `body` into an inline MIME part. Assets that
+    /// were sent in the request are used first, anything else is pulled from the template resolver.
+    #[allow(clippy::result_large_err)]
+    async fn inline_assets(
+        &self,
+        body: &Body,
+        mut provided: Vec<(String, SinglePart)>,
+        cache: &mut PullCache,
+    ) -> Result<Vec<SinglePart>, Status> {
+        let Some(ref html) = body.html else {
+            return Ok(vec![]);
+        };
+
+        let mut parts = vec![];
+        for content_id in mime::content_ids(html) {
+            if let Some(idx) = provided.iter().position(|(id, _)| *id == content_id) {
+                parts.push(provided.remove(idx).1);
+                continue;
+            }
+
+            let contents = self.pull_asset(&content_id, cache).await?;
+            parts
+                .push(Attachment::new_inline(content_id.clone()).body(contents, mime::guess_content_type(&content_id)));
+        }
+
+        // assets that weren't referenced in the HTML are still included, as they
+        // might be referenced in a way that we didn't pick up.
+        parts.extend(provided.into_iter().map(|(_, part)| part));
+        Ok(parts)
+    }
+
+    /// Pulls an inline asset from the template resolver if it isn't in `cache` already.
+    #[allow(clippy::result_large_err)]
+    async fn pull_asset(&self, content_id: &str, cache: &mut PullCache) -> Result<Vec<u8>, Status> {
+        if !cache.assets.contains_key(content_id) {
+            let pulled = match self.templates.resolver().pull_bytes(PathBuf::from(content_id)).await {
+                Ok(Some(contents)) => Ok(contents),
+                Ok(None) => {
+                    warn!(%content_id, "unknown inline asset");
+                    Err(Status::invalid_argument(format!(
+                        "unknown inline asset 'cid:{content_id}'"
+                    )))
+                }
+
+                Err(e) => {
+                    error!(%content_id, error = %e, "unable to pull inline asset");
+                    sentry::capture_error(&*e);
+
+                    Err(Status::internal("Internal Server Error"))
+                }
+            };
+
+            cache.assets.insert(content_id.to_owned(), pulled);
+        }
+
+        cache.assets[content_id].clone()
+    }
+
+    /// Pulls and compiles `template` if it isn't in `cache` already.
+    #[allow(clippy::result_large_err)]
+    async fn compile_template<'a>(
+        &self,
+        template: &str,
+        cache: &'a mut PullCache,
+    ) -> Result<&'a Arc<CompiledTemplate>, Status> {
+        if !cache.templates.contains_key(template) {
+            let compiled = self.pull_and_compile(template).await;
+            cache.templates.insert(template.to_owned(), compiled);
+        }
+
+        cache.templates[template].as_ref().map_err(Clone::clone)
+    }
+
+    /// Pulls and compiles `template`, or uses the compiled template from the [`TemplateCache`] if
+    /// it's still cached.
+    #[allow(clippy::result_large_err)]
+    async fn pull_and_compile(&self, template: &str) -> Result<Arc<CompiledTemplate>, Status> {
+        match self.templates.pull(template).await {
+            Ok(Some(compiled)) => Ok(compiled),
+            Ok(None) => {
+                warn!(%template, "unknown template");
+                Err(Status::invalid_argument(format!("unknown template '{template}'")))
             }
 
             Err(e) => {
-                return Ok(Response::new(SendEmailResponse {
+                error!(%template, error = %e, "unable to pull template");
+                sentry::capture_error(&*e);
+
+                Err(Status::internal("Internal Server Error"))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Emails for Service {
+    async fn ping(&self, _: Request<PingRequest>) -> Result<Response<PingResponse>, Status> {
+        Ok(Response::new(PingResponse { pong: true }))
+    }
+
+    async fn send(&self, request: Request<SendEmailRequest>) -> Result<Response<SendEmailResponse>, Status> {
+        self.send_email(request.get_ref(), &mut PullCache::default())
+            .await
+            .map(Response::new)
+    }
+
+    async fn send_batch(&self, request: Request<SendBatchRequest>) -> Result<Response<SendBatchResponse>, Status> {
+        let requests = &request.get_ref().requests;
+        debug!(emails = requests.len(), "sending batch of emails");
+
+        let limits = &self.config.batch;
+        if requests.len() as u64 > limits.max_size {
+            return Err(Status::invalid_argument(format!(
+                "batch has {} emails, which is over the limit of {}",
+                requests.len(),
+                limits.max_size
+            )));
+        }
+
+        // every email is rendered first, so each template is only compiled once, and then
+        // delivered concurrently. a failure for one email shouldn't fail the whole batch,
+        // so they're reported in that email's response instead.
+        let mut cache = PullCache::default();
+        let mut messages = Vec::with_capacity(requests.len());
+        for request in requests {
+            messages.push(match self.render_email(request, &mut cache, false).await {
+                Ok(rendered) => Ok(rendered.message),
+                Err(Rejected::Invalid(error)) => Err(failed(error)),
+                Err(Rejected::Status(status)) => Err(failed(status_to_error(&status))),
+            });
+        }
+
+        let deliveries = requests
+            .iter()
+            .zip(messages)
+            .map(|(request, message)| self.deliver_batched(request, message))
+            .collect::<Vec<_>>();
+
+        let responses = stream::iter(deliveries)
+            .buffered(usize::from(limits.concurrency.max(1)))
+            .collect()
+            .await;
+
+        Ok(Response::new(SendBatchResponse { responses }))
+    }
+
+    async fn get_status(&self, request: Request<GetStatusRequest>) -> Result<Response<GetStatusResponse>, Status> {
+        let id = &request.get_ref().message_id;
+        let message = self.queue.get(id).await.map_err(|e| {
+            error!(%id, error = %e, "unable to get queued message");
+            sentry::capture_error(&*e);
+
+            Status::internal("Internal Server Error")
+        })?;
+
+        let Some(message) = message else {
+            return Err(Status::not_found(format!("unknown message '{id}'")));
+        };
+
+        Ok(Response::new(GetStatusResponse {
+            message_id: message.id,
+            state: delivery_state(message.state).into(),
+            attempts: message.attempts,
+            last_response: message.last_response,
+            queued_at: Some(timestamp(message.queued_at)),
+            updated_at: Some(timestamp(message.updated_at)),
+            next_attempt_at: message.next_attempt_at.map(timestamp),
+        }))
+    }
+
+    async fn render(&self, request: Request<SendEmailRequest>) -> Result<Response<RenderResponse>, Status> {
+        let rendered = match self
+            .render_email(request.get_ref(), &mut PullCache::default(), true)
+            .await
+        {
+            Ok(rendered) => rendered,
+            Err(Rejected::Invalid(error)) => {
+                return Ok(Response::new(RenderResponse {
                     success: false,
-                    errors: vec![Error {
-                        code: String::from("UNABLE_TO_SEND_EMAIL"),
-                        message: e.to_string(),
-                        details: None,
-                    }],
+                    errors: vec![error],
+                    ..Default::default()
                 }))
             }
+
+            Err(Rejected::Status(status)) => return Err(status),
+        };
+
+        Ok(Response::new(RenderResponse {
+            success: true,
+            errors: vec![],
+            subject: rendered.subject,
+            text: rendered.body.text_or_generated(),
+            html: rendered.body.html,
+            raw: rendered.message.formatted(),
+        }))
+    }
+
+    async fn list_templates(
+        &self,
+        _request: Request<ListTemplatesRequest>,
+    ) -> Result<Response<ListTemplatesResponse>, Status> {
+        let paths = self.templates.resolver().list().await.map_err(|e| {
+            error!(error = %e, "unable to list templates");
+            sentry::capture_error(&*e);
+
+            Status::internal("Internal Server Error")
+        })?;
+
+        Ok(Response::new(ListTemplatesResponse {
+            templates: CompiledTemplate::names(paths),
+        }))
+    }
+
+    async fn cancel_scheduled(&self, request: Request<CancelScheduledRequest>) -> Result<Response<()>, Status> {
+        let id = &request.get_ref().message_id;
+        let message = self.queue.get(id).await.map_err(|e| {
+            error!(%id, error = %e, "unable to get queued message");
+            sentry::capture_error(&*e);
+
+            Status::internal("Internal Server Error")
+        })?;
+
+        let Some(message) = message else {
+            return Err(Status::not_found(format!("unknown message '{id}'")));
+        };
+
+        if message.state != queue::DeliveryState::Scheduled {
+            return Err(Status::failed_precondition(format!("message '{id}' is not scheduled")));
+        }
+
+        let cancelled = self.queue.cancel(message).await.map_err(|e| {
+            error!(%id, error = %e, "unable to cancel scheduled message");
+            sentry::capture_error(&*e);
+
+            Status::internal("Internal Server Error")
+        })?;
+
+        // a worker might have started to deliver it since it was looked up
+        if !cancelled {
+            return Err(Status::failed_precondition(format!("message '{id}' is not scheduled")));
         }
+
+        Ok(Response::new(()))
     }
 }
 
-fn prost_value_to_data_type(value: Kind) -> Data {
-    match value {
-        Kind::StringValue(s) => Data::String(s),
-        Kind::NumberValue(num) => Data::String(num.to_string()),
-        Kind::BoolValue(b) => Data::Bool(b),
-        Kind::NullValue(_) => Data::Null,
-        Kind::StructValue(s) => {
-            let mut res = HashMap::new();
-            for (key, value) in s.fields {
-                if value.kind.is_none() {
-                    warn!(kind = ?value.kind, %key, "cannot determine Mustache type for key with Protobuf type; skipping");
-                    continue;
-                }
+/// Validated mailboxes for every recipient of a [`SendEmailRequest`].
+struct Recipients {
+    to: Vec<Mailbox>,
+    cc: Vec<Mailbox>,
+    bcc: Vec<Mailbox>,
+    reply_to: Vec<Mailbox>,
+}
 
-                res.insert(key, prost_value_to_data_type(value.kind.unwrap()));
-            }
+impl TryFrom<&SendEmailRequest> for Recipients {
+    type Error = Error;
+
+    fn try_from(request: &SendEmailRequest) -> Result<Self, Self::Error> {
+        let mut to = mailboxes("recipients", &request.recipients)?;
+        if !request.to.is_empty() {
+            let address = parse_address("to", &request.to)?;
+            to.insert(0, Mailbox::new(None, address));
+        }
+
+        Ok(Recipients {
+            to,
+            cc: mailboxes("cc", &request.cc)?,
+            bcc: mailboxes("bcc", &request.bcc)?,
+            reply_to: mailboxes("reply_to", &request.reply_to)?,
+        })
+    }
+}
+
+impl Recipients {
+    /// Whether there is nobody to send the email to. Reply-To addresses don't count, as
+    /// they don't receive the email.
+    fn is_empty(&self) -> bool {
+        self.to.is_empty() && self.cc.is_empty() && self.bcc.is_empty()
+    }
+}
+
+/// Validates every [`Recipient`] in `recipients` and maps them into a [`Mailbox`].
+fn mailboxes(field: &str, recipients: &[Recipient]) -> Result<Vec<Mailbox>, Error> {
+    recipients
+        .iter()
+        .map(|recipient| {
+            parse_address(field, &recipient.address).map(|address| Mailbox::new(recipient.name.clone(), address))
+        })
+        .collect()
+}
+
+fn parse_address(field: &str, address: &str) -> Result<Address, Error> {
+    address.parse::<Address>().map_err(|e| {
+        warn!(%address, field, error = %e, "received invalid address");
+        Error {
+            code: String::from("INVALID_ADDRESS"),
+            message: format!("invalid address '{address}' in 'request.{field}': {e}"),
+            ..Default::default()
+        }
+    })
+}
+
+/// Creates a [`SendEmailResponse`] that failed with a single [`Error`].
+fn failed(error: Error) -> SendEmailResponse {
+    SendEmailResponse {
+        success: false,
+        errors: vec![error],
+        message_id: None,
+    }
+}
 
-            Data::Map(res)
+fn delivery_state(state: queue::DeliveryState) -> DeliveryState {
+    match state {
+        queue::DeliveryState::Queued => DeliveryState::Queued,
+        queue::DeliveryState::Sending => DeliveryState::Sending,
+        queue::DeliveryState::Delivered => DeliveryState::Delivered,
+        queue::DeliveryState::Deferred => DeliveryState::Deferred,
+        queue::DeliveryState::Failed => DeliveryState::Failed,
+        queue::DeliveryState::Scheduled => DeliveryState::Scheduled,
+        queue::DeliveryState::Cancelled => DeliveryState::Cancelled,
+    }
+}
+
+fn parse_send_at(timestamp: Timestamp) -> Result<DateTime<Utc>, Error> {
+    u32::try_from(timestamp.nanos)
+        .ok()
+        .and_then(|nanos| DateTime::from_timestamp(timestamp.seconds, nanos))
+        .ok_or_else(|| Error {
+            code: String::from("INVALID_ARG"),
+            message: format!("'request.send_at' is not a valid timestamp: {timestamp}"),
+            ..Default::default()
+        })
+}
+
+fn timestamp(time: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.timestamp_subsec_nanos() as i32,
+    }
+}
+
+/// Creates the `details` of an [`Error`] from a list of fields.
+fn details<const N: usize>(fields: [(&str, Kind); N]) -> Struct {
+    Struct {
+        fields: fields
+            .into_iter()
+            .map(|(key, kind)| (key.to_owned(), Value { kind: Some(kind) }))
+            .collect(),
+    }
+}
+
+/// Maps a [`Status`] into an [`Error`] that can be reported in a [`SendEmailResponse`].
+fn status_to_error(status: &Status) -> Error {
+    let code = match status.code() {
+        Code::InvalidArgument => "INVALID_ARG",
+        _ => "INTERNAL_SERVER_ERROR",
+    };
+
+    Error {
+        code: String::from(code),
+        message: status.message().to_owned(),
+        ..Default::default()
+    }
+}
+
+/// Maps a Protobuf value into JSON, which is the context that templates are validated and rendered
+/// with. Whole numbers are mapped into integers, since Protobuf only has doubles.
+fn prost_value_to_json(value: Kind) -> serde_json::Value {
+    match value {
+        Kind::StringValue(s) => serde_json::Value::String(s),
+        Kind::NumberValue(num) if num.fract() == 0.0 && num.abs() < i64::MAX as f64 => {
+            serde_json::Value::from(num as i64)
         }
 
-        Kind::ListValue(ListValue { values }) => Data::Vec(
+        Kind::NumberValue(num) => serde_json::Number::from_f64(num)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+
+        Kind::BoolValue(b) => serde_json::Value::Bool(b),
+        Kind::NullValue(_) => serde_json::Value::Null,
+        Kind::StructValue(s) => serde_json::Value::Object(
+            s.fields
+                .into_iter()
+                .filter_map(|(key, value)| value.kind.map(|kind| (key, prost_value_to_json(kind))))
+                .collect(),
+        ),
+
+        Kind::ListValue(ListValue { values }) => serde_json::Value::Array(
             values
-                .iter()
-                .enumerate()
-                .filter_map(|(idx, val)| match val.kind.clone() {
-                    Some(kind) => Some(prost_value_to_data_type(kind)),
-                    None => {
-                        warn!(
-                            index = idx,
-                            "cannot determine Mustache type for index in Protobuf list; skipping"
-                        );
-
-                        None
-                    }
-                })
+                .into_iter()
+                .map(|value| value.kind.map_or(serde_json::Value::Null, prost_value_to_json))
                 .collect(),
         ),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{templates::resolver::testing::CountingTemplateResolver, Attachment as AttachedFile, InlineAsset};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Creates a [`Service`] that resolves templates with `resolver` and never connects to
+    /// a SMTP server.
+    fn service(resolver: impl TemplateResolver + 'static) -> Service {
+        service_with_config(Config::default(), resolver)
+    }
+
+    /// Same as [`service`], but with the given [`Config`].
+    fn service_with_config(config: Config, resolver: impl TemplateResolver + 'static) -> Service {
+        let mailer = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("localhost").build();
+
+        Service {
+            _sentry_guard: None,
+            templates: Arc::new(TemplateCache::new(Box::new(resolver), &config.cache)),
+            queue: Queue::new(Arc::new(MemoryStorage::default()), mailer.clone(), config.queue.clone()),
+            config,
+            mailer,
+        }
+    }
+
+    #[tokio::test]
+    async fn render_rejects_paths_outside_of_templates() {
+        let root = std::env::temp_dir().join(format!("emails-render-{}", std::process::id()));
+        let directory = root.join("templates");
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(root.join("secret.txt"), "hunter2").unwrap();
+        std::fs::write(directory.join("logo.png"), "png").unwrap();
+
+        let service = service(FilesystemTemplateResolver::new(remi_fs::FilesystemStorageConfig::new(
+            directory.to_string_lossy().into_owned(),
+        )));
+
+        let render = |html: Option<&str>, template: Option<&str>| {
+            service.render(Request::new(SendEmailRequest {
+                html_content: html.map(String::from),
+                template: template.map(String::from),
+                ..Default::default()
+            }))
+        };
+
+        let rendered = render(Some(r#"<img src="cid:logo.png">"#), None).await.unwrap();
+        assert!(rendered.get_ref().success);
+
+        for html in [r#"<img src="cid:/etc/passwd">"#, r#"<img src="cid:../secret.txt">"#] {
+            assert!(render(Some(html), None).await.is_err(), "{html} should be rejected");
+        }
+
+        for template in ["/etc/passwd", "../secret"] {
+            assert!(
+                render(None, Some(template)).await.is_err(),
+                "{template} should be rejected"
+            );
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn render_rejects_inline_assets_without_html() {
+        let service = service(FilesystemTemplateResolver::new(remi_fs::FilesystemStorageConfig::new(
+            std::env::temp_dir().to_string_lossy().into_owned(),
+        )));
+
+        let rendered = service
+            .render(Request::new(SendEmailRequest {
+                text_content: Some(String::from("Hello!")),
+                inline_assets: vec![InlineAsset {
+                    content_id: String::from("logo.png"),
+                    content_type: String::from("image/png"),
+                    content: b"png".to_vec(),
+                }],
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert!(!rendered.success);
+        assert_eq!(rendered.errors[0].code, "INVALID_ARG");
+    }
+
+    #[tokio::test]
+    async fn send_batch_compiles_each_template_once() {
+        // disable the template cache, so only the batch itself can skip compiling
+        let mut config = Config::default();
+        config.cache.max_templates = 0;
+
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let service = service_with_config(config, CountingTemplateResolver(pulled.clone()));
+        let request = |template: &str| SendEmailRequest {
+            to: String::from("noel@noelware.org"),
+            template: Some(template.to_owned()),
+            queue: true,
+            ..Default::default()
+        };
+
+        let responses = service
+            .send_batch(Request::new(SendBatchRequest {
+                requests: vec![request("welcome"), request("goodbye"), request("welcome")],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .responses;
+
+        assert!(responses.iter().all(|response| response.success));
+        assert_eq!(pulled.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn send_batch_keeps_order_and_limits_size() {
+        let mut config = Config::default();
+        config.batch.max_size = 3;
+        config.batch.concurrency = 2;
+
+        let service = service_with_config(config, CountingTemplateResolver(Arc::default()));
+        let request = |to: &str| SendEmailRequest {
+            to: to.to_owned(),
+            template: Some(String::from("welcome")),
+            queue: true,
+            ..Default::default()
+        };
+
+        let responses = service
+            .send_batch(Request::new(SendBatchRequest {
+                requests: vec![request("noel@noelware.org"), request(""), request("ice@noelware.org")],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .responses;
+
+        let succeeded = responses.iter().map(|response| response.success).collect::<Vec<_>>();
+        assert_eq!(succeeded, vec![true, false, true]);
+        assert_eq!(responses[1].errors[0].code, "MISSING_ARG");
+
+        let status = service
+            .send_batch(Request::new(SendBatchRequest {
+                requests: vec![request("noel@noelware.org"); 4],
+            }))
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn send_rejects_invalid_attachments() {
+        let mut config = Config::default();
+        config.attachments.max_size = 4;
+        config.attachments.max_total_size = 6;
+
+        let service = service_with_config(
+            config,
+            FilesystemTemplateResolver::new(remi_fs::FilesystemStorageConfig::new(
+                std::env::temp_dir().to_string_lossy().into_owned(),
+            )),
+        );
+
+        let file = |filename: &str, content_type: &str, content: &[u8]| AttachedFile {
+            filename: filename.to_owned(),
+            content_type: content_type.to_owned(),
+            content: content.to_vec(),
+        };
+
+        let cases = [
+            (vec![file("large.txt", "text/plain", b"12345")], "ATTACHMENT_TOO_LARGE"),
+            (
+                vec![
+                    file("a.txt", "text/plain", b"1234"),
+                    file("b.txt", "text/plain", b"1234"),
+                ],
+                "ATTACHMENTS_TOO_LARGE",
+            ),
+            (vec![file("a.txt", "not a content type", b"1234")], "INVALID_ATTACHMENT"),
+        ];
+
+        for (attachments, code) in cases {
+            let response = service
+                .send(Request::new(SendEmailRequest {
+                    to: String::from("noel@noelware.org"),
+                    text_content: Some(String::from("Hello!")),
+                    attachments,
+                    ..Default::default()
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+
+            assert!(!response.success);
+            assert_eq!(response.errors[0].code, code);
+        }
+    }
+}
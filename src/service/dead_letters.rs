@@ -0,0 +1,110 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::timestamp;
+use crate::{
+    queue::{DeliveryState, Queue, QueuedMessage},
+    DeadLetter, DeadLetterRequest, DeadLetters, ListDeadLettersRequest, ListDeadLettersResponse,
+};
+use tonic::{Request, Response, Status};
+use tracing::error;
+
+/// Represents an implementation of the `DeadLetters` gRPC service, which allows
+/// on-call to recover dead letters from the delivery [`Queue`].
+pub(crate) struct DeadLetterService {
+    queue: Queue,
+}
+
+impl DeadLetterService {
+    pub(crate) fn new(queue: Queue) -> DeadLetterService {
+        DeadLetterService { queue }
+    }
+
+    /// Returns the dead letter with the given ID, or a `NOT_FOUND` status if it doesn't
+    /// exist or wasn't a dead letter.
+    #[allow(clippy::result_large_err)]
+    async fn find(&self, id: &str) -> Result<QueuedMessage, Status> {
+        let message = self.queue.get(id).await.map_err(|e| {
+            error!(%id, error = %e, "unable to get dead letter");
+            sentry::capture_error(&*e);
+
+            Status::internal("Internal Server Error")
+        })?;
+
+        match message {
+            Some(message) if message.state == DeliveryState::Failed => Ok(message),
+            _ => Err(Status::not_found(format!("unknown dead letter '{id}'"))),
+        }
+    }
+}
+
+#[async_trait]
+impl DeadLetters for DeadLetterService {
+    async fn list(&self, _: Request<ListDeadLettersRequest>) -> Result<Response<ListDeadLettersResponse>, Status> {
+        let messages = self.queue.dead_letters().await.map_err(|e| {
+            error!(error = %e, "unable to list dead letters");
+            sentry::capture_error(&*e);
+
+            Status::internal("Internal Server Error")
+        })?;
+
+        Ok(Response::new(ListDeadLettersResponse {
+            dead_letters: messages
+                .into_iter()
+                .map(|message| dead_letter(message, false))
+                .collect(),
+        }))
+    }
+
+    async fn get(&self, request: Request<DeadLetterRequest>) -> Result<Response<DeadLetter>, Status> {
+        let message = self.find(&request.get_ref().message_id).await?;
+        Ok(Response::new(dead_letter(message, true)))
+    }
+
+    async fn replay(&self, request: Request<DeadLetterRequest>) -> Result<Response<()>, Status> {
+        let message = self.find(&request.get_ref().message_id).await?;
+        self.queue.replay(message).await.map(Response::new).map_err(|e| {
+            error!(error = %e, "unable to replay dead letter");
+            sentry::capture_error(&*e);
+
+            Status::internal("Internal Server Error")
+        })
+    }
+
+    async fn discard(&self, request: Request<DeadLetterRequest>) -> Result<Response<()>, Status> {
+        let message = self.find(&request.get_ref().message_id).await?;
+        self.queue.discard(&message.id).await.map_err(|e| {
+            error!(error = %e, "unable to discard dead letter");
+            sentry::capture_error(&*e);
+
+            Status::internal("Internal Server Error")
+        })?;
+
+        Ok(Response::new(()))
+    }
+}
+
+fn dead_letter(message: QueuedMessage, include_content: bool) -> DeadLetter {
+    DeadLetter {
+        sender: message.envelope.from().map(ToString::to_string),
+        recipients: message.envelope.to().iter().map(ToString::to_string).collect(),
+        attempts: message.attempts,
+        last_error: message.last_response,
+        queued_at: Some(timestamp(message.queued_at)),
+        failed_at: Some(timestamp(message.updated_at)),
+        content: include_content.then_some(message.raw),
+        message_id: message.id,
+    }
+}
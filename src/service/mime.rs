@@ -0,0 +1,129 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::templates::compiled::Body;
+use lettre::{
+    message::{header::ContentType, MessageBuilder, MultiPart, SinglePart},
+    Message,
+};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::path::Path;
+
+/// Matches a `cid:` URL in a `src` or `href` attribute or a CSS `url()`, i.e.
+/// `<img src="cid:logo.png">` or `url(cid:logo.png)`.
+static CID_URL: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?i)(?:\b(?:src|href)\s*=\s*["']?|url\(\s*["']?)cid:([^"'\s)>]+)"#).unwrap());
+
+/// Represents the MIME structure of an email's body.
+pub(crate) enum MimeBody {
+    Single(SinglePart),
+    Multi(MultiPart),
+}
+
+impl MimeBody {
+    /// Builds the MIME structure from a [`Body`]. If the body has a HTML part, then it'll
+    /// be sent as `multipart/alternative`, with the HTML and `inline` parts wrapped in a
+    /// `multipart/related` part. Everything is wrapped in a `multipart/mixed` part if there
+    /// are any attachments.
+    pub(crate) fn new(body: Body, inline: Vec<SinglePart>, attachments: Vec<SinglePart>) -> MimeBody {
+        let content = match body.html.clone() {
+            Some(html) => {
+                let alternative = MultiPart::alternative()
+                    .singlepart(SinglePart::plain(body.text_or_generated().unwrap_or_default()));
+
+                MimeBody::Multi(match inline.is_empty() {
+                    true => alternative.singlepart(SinglePart::html(html)),
+                    false => alternative.multipart(inline.into_iter().fold(
+                        MultiPart::related().singlepart(SinglePart::html(html)),
+                        MultiPart::singlepart,
+                    )),
+                })
+            }
+
+            None => MimeBody::Single(SinglePart::plain(body.text.unwrap_or_default())),
+        };
+
+        if attachments.is_empty() {
+            return content;
+        }
+
+        let mixed = match content {
+            MimeBody::Single(part) => MultiPart::mixed().singlepart(part),
+            MimeBody::Multi(part) => MultiPart::mixed().multipart(part),
+        };
+
+        MimeBody::Multi(attachments.into_iter().fold(mixed, MultiPart::singlepart))
+    }
+
+    /// Finishes building a [`Message`] with this body.
+    pub(crate) fn build(self, builder: MessageBuilder) -> Result<Message, lettre::error::Error> {
+        match self {
+            MimeBody::Single(part) => builder.singlepart(part),
+            MimeBody::Multi(part) => builder.multipart(part),
+        }
+    }
+}
+
+/// Returns every distinct Content-ID that is referenced by a `cid:` URL in `html`, in the
+/// order that they appear.
+pub(crate) fn content_ids(html: &str) -> Vec<String> {
+    let mut ids: Vec<String> = vec![];
+    for captures in CID_URL.captures_iter(html) {
+        let id = &captures[1];
+        if !ids.iter().any(|existing| existing == id) {
+            ids.push(id.to_owned());
+        }
+    }
+
+    ids
+}
+
+/// Guesses the content type of an inline asset from its file extension.
+pub(crate) fn guess_content_type(path: &str) -> ContentType {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+
+    let mime = match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    };
+
+    ContentType::parse(mime).expect("valid content type")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::content_ids;
+
+    #[test]
+    fn content_ids_are_distinct_and_ordered() {
+        let html =
+            r#"<img src="cid:logo.png"><div style="background: url(cid:assets/bg.png)"></div><img src='cid:logo.png'>"#;
+        assert_eq!(content_ids(html), vec!["logo.png", "assets/bg.png"]);
+    }
+
+    #[test]
+    fn content_ids_ignore_cid_urls_outside_of_attributes() {
+        let html = r#"<p>Embed images with cid:logo.png</p><a href="cid:terms.pdf">terms</a>"#;
+        assert_eq!(content_ids(html), vec!["terms.pdf"]);
+    }
+}
@@ -13,28 +13,52 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod cache;
+pub mod compiled;
+pub mod engine;
+pub mod front_matter;
 pub mod resolver;
 
-use crate::{config::TryFromEnv, var};
+use crate::{
+    config::{merge::Merge, FromEnv, TryFromEnv},
+    var,
+};
 use eyre::Report;
 use remi_fs::FilesystemStorageConfig;
+use remi_s3::S3StorageConfig;
+use resolver::{email_template, git, kubernetes};
 use serde::{Deserialize, Serialize};
+use std::path::Path;
 
 /// Represents the configuration for how to resolve templates.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
+#[serde(rename_all = "lowercase")]
 pub enum Config {
     /// Uses the local filesystem to find and use templates from. All files
     /// must be valid UTF-8 or the server will panic, but won't crash
     /// the whole program.
+    #[serde(alias = "fs")]
     Filesystem(FilesystemStorageConfig),
 
     /// Uses the Kubernetes API to resolve templates from a [`ConfigMap`](https://kubernetes.io/docs/concepts/configuration/configmap) reference.
-    Kubernetes,
+    Kubernetes(kubernetes::Config),
 
-    /// Uses a Git repository to resolve templates from. It'll be mounted into `${templates.git.directory}/templates`. The
-    /// resolver also supports SSH connections.
-    Git,
+    /// Uses [`EmailTemplate`](email_template::EmailTemplate) custom resources in a Kubernetes cluster to
+    /// resolve templates from.
+    #[serde(rename = "email_templates")]
+    EmailTemplates(email_template::Config),
+
+    /// Uses a Git repository to resolve templates from. It'll be cloned into `${templates.git.directory}` and
+    /// fetched periodically. The resolver supports both HTTPS tokens and SSH keys.
+    Git(Box<git::Config>),
+
+    /// Uses an S3-compatible bucket (i.e, AWS S3 or MinIO) to resolve templates from, so templates
+    /// can be deployed as artifacts rather than mounted as a volume.
+    S3(Box<S3StorageConfig>),
+
+    /// Tries a list of resolvers in order and uses the first one that has the template, i.e,
+    /// to override templates from a Git repository with ConfigMaps.
+    Chain(Vec<Config>),
 }
 
 impl Default for Config {
@@ -44,21 +68,49 @@ impl Default for Config {
     }
 }
 
+impl Merge for Config {
+    fn merge(&mut self, other: Self) {
+        // `other` falls back to the default when it wasn't configured (i.e, the YAML file has no
+        // `templates` block), which shouldn't override resolvers from environment variables.
+        if !other.is_default() {
+            *self = other;
+        }
+    }
+}
+
+impl Config {
+    fn is_default(&self) -> bool {
+        matches!(self, Config::Filesystem(config) if config.directory() == Path::new("./templates"))
+    }
+}
+
 impl TryFromEnv for Config {
     type Output = Config;
     type Err = Report;
 
     fn try_from_env() -> Result<Self::Output, Self::Err> {
-        match var!("EMAILS_TEMPLATE_RESOLVER", is_optional: true) {
-            Some(resolver) => match resolver.as_str() {
+        let Some(resolvers) = var!("EMAILS_TEMPLATE_RESOLVER", is_optional: true) else {
+            return Ok(Default::default());
+        };
+
+        // a comma-separated list of resolvers, i.e, `kubernetes,git`, is used as a chain
+        let mut resolvers = resolvers
+            .split(',')
+            .map(|resolver| match resolver.trim() {
                 "filesystem" | "fs" => Ok(Default::default()),
-                "kubernetes" => Ok(Config::Kubernetes),
-                "git" => Ok(Config::Git),
+                "kubernetes" => Ok(Config::Kubernetes(kubernetes::Config::from_env())),
+                "email_templates" => Ok(Config::EmailTemplates(email_template::Config::from_env())),
+                "git" => Ok(Config::Git(Box::new(git::Config::try_from_env()?))),
+                "s3" => Ok(Config::S3(Box::new(S3StorageConfig::try_from_env()?))),
                 resolver => Err(eyre!(
-                    "wanted [filesystem/fs, kubernetes, git]; received {resolver} instead"
+                    "wanted [filesystem/fs, kubernetes, email_templates, git, s3]; received {resolver} instead"
                 )),
-            },
-            None => Ok(Default::default()),
+            })
+            .collect::<Result<Vec<_>, Report>>()?;
+
+        match resolvers.len() {
+            1 => Ok(resolvers.remove(0)),
+            _ => Ok(Config::Chain(resolvers)),
         }
     }
 }
@@ -0,0 +1,244 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{
+    compiled::CompiledTemplate,
+    engine,
+    resolver::{Change, TemplateResolver},
+};
+use crate::config::cache::Config;
+use eyre::Result;
+use lru::LruCache;
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+/// Wraps a [`TemplateResolver`] and keeps the templates that were [compiled][CompiledTemplate]
+/// from it in memory by the name they were pulled with, so the same template isn't compiled
+/// on every request.
+///
+/// The least recently used templates are evicted once `cache.max_templates` templates are
+/// cached, and templates expire after `cache.ttl` seconds. Resolvers that can report
+/// [changes][TemplateResolver::changes] also evict templates as soon as their files change.
+pub struct TemplateCache {
+    resolver: Box<dyn TemplateResolver>,
+    templates: Option<Mutex<LruCache<String, Cached>>>,
+    ttl: Option<Duration>,
+    generation: AtomicU64,
+}
+
+struct Cached {
+    template: Arc<CompiledTemplate>,
+    compiled_at: Instant,
+}
+
+impl TemplateCache {
+    /// Creates a new [`TemplateCache`] around `resolver`.
+    pub fn new(resolver: Box<dyn TemplateResolver>, config: &Config) -> TemplateCache {
+        TemplateCache {
+            resolver,
+            templates: usize::try_from(config.max_templates)
+                .ok()
+                .and_then(NonZeroUsize::new)
+                .map(|max| Mutex::new(LruCache::new(max))),
+            ttl: (config.ttl > 0).then(|| Duration::from_secs(config.ttl)),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the [`TemplateResolver`] that templates are pulled from.
+    pub fn resolver(&self) -> &dyn TemplateResolver {
+        self.resolver.as_ref()
+    }
+
+    /// Returns the compiled template called `name`, which is [pulled][CompiledTemplate::pull]
+    /// and compiled if it wasn't cached or has expired.
+    pub async fn pull(&self, name: &str) -> Result<Option<Arc<CompiledTemplate>>> {
+        let Some(ref templates) = self.templates else {
+            return Ok(CompiledTemplate::pull(self.resolver(), name).await?.map(Arc::new));
+        };
+
+        if let Some(cached) = templates.lock().unwrap().get(name) {
+            if self.ttl.map_or(true, |ttl| cached.compiled_at.elapsed() < ttl) {
+                return Ok(Some(cached.template.clone()));
+            }
+        }
+
+        let generation = self.generation.load(Ordering::Acquire);
+        let Some(template) = CompiledTemplate::pull(self.resolver(), name).await?.map(Arc::new) else {
+            return Ok(None);
+        };
+
+        // the template might have been compiled from files that changed in the meantime
+        let mut templates = templates.lock().unwrap();
+        if self.generation.load(Ordering::Acquire) == generation {
+            templates.put(
+                name.to_owned(),
+                Cached {
+                    template: template.clone(),
+                    compiled_at: Instant::now(),
+                },
+            );
+        }
+
+        Ok(Some(template))
+    }
+
+    /// Evicts all templates that could have been compiled from what changed.
+    pub fn invalidate(&self, change: &Change) {
+        let Some(ref templates) = self.templates else {
+            return;
+        };
+
+        let mut templates = templates.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        match change {
+            Change::All => templates.clear(),
+            Change::Path(path) => {
+                let stale = templates
+                    .iter()
+                    .filter(|(name, _)| depends_on(name, path))
+                    .map(|(name, _)| name.clone())
+                    .collect::<Vec<_>>();
+
+                for name in stale {
+                    templates.pop(&name);
+                }
+            }
+        }
+    }
+
+    /// Spawns a task that evicts templates whenever the resolver reports a change.
+    pub fn watch(self: &Arc<Self>) {
+        let Some(mut changes) = self.resolver.changes() else {
+            return;
+        };
+
+        let cache = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                match changes.recv().await {
+                    Ok(change) => {
+                        debug!(?change, "invalidating compiled templates");
+                        cache.invalidate(&change);
+                    }
+
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(
+                            skipped,
+                            "missed changes to templates, invalidating all compiled templates"
+                        );
+                        cache.invalidate(&Change::All);
+                    }
+
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+/// Returns whether the template called `name` could have been compiled from `path`. Templates
+/// are compiled from files that share their name without an extension (i.e, `welcome.html`,
+/// `welcome.txt`, `welcome.subject` and `welcome.schema.json` for both the `welcome` and
+/// `welcome.html` templates).
+fn depends_on(name: &str, path: &str) -> bool {
+    let name = name.strip_prefix("./").unwrap_or(name);
+    let stem = without_extension(path);
+    name == stem || without_extension(name) == stem
+}
+
+fn without_extension(path: &str) -> &str {
+    if let Some(stem) = path.strip_suffix(".schema.json") {
+        return stem;
+    }
+
+    // i.e, `welcome.html.hbs`
+    let path = match path.rsplit_once('.') {
+        Some((stem, ext)) if engine::extensions().any(|engine| engine.eq_ignore_ascii_case(ext)) => stem,
+        _ => path,
+    };
+
+    match path.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.contains('/') => stem,
+        _ => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{depends_on, TemplateCache};
+    use crate::{
+        config::cache::Config,
+        templates::resolver::{testing::CountingTemplateResolver, Change},
+    };
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[tokio::test]
+    async fn templates_are_compiled_once_until_they_change() {
+        let config = Config {
+            max_templates: 1,
+            ttl: 0,
+        };
+
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let pulls = || pulled.load(Ordering::SeqCst);
+        let cache = TemplateCache::new(Box::new(CountingTemplateResolver(pulled.clone())), &config);
+        cache.pull("welcome").await.unwrap().unwrap();
+        cache.pull("welcome").await.unwrap().unwrap();
+        assert_eq!(pulls(), 1);
+
+        cache.invalidate(&Change::Path(String::from("goodbye.txt")));
+        cache.pull("welcome").await.unwrap().unwrap();
+        assert_eq!(pulls(), 1);
+
+        cache.invalidate(&Change::Path(String::from("welcome.txt")));
+        cache.pull("welcome").await.unwrap().unwrap();
+        assert_eq!(pulls(), 2);
+
+        // only one template fits, so `welcome` is evicted
+        cache.pull("goodbye").await.unwrap().unwrap();
+        cache.pull("welcome").await.unwrap().unwrap();
+        assert_eq!(pulls(), 4);
+    }
+
+    #[test]
+    fn templates_depend_on_files_with_the_same_name() {
+        assert!(depends_on("welcome", "welcome.html"));
+        assert!(depends_on("welcome", "welcome.subject"));
+        assert!(depends_on("welcome.html", "welcome.txt"));
+        assert!(depends_on("plain.tmpl", "plain.subject"));
+        assert!(depends_on("welcome.html", "welcome.schema.json"));
+        assert!(depends_on("welcome", "welcome.txt.hbs"));
+        assert!(depends_on("welcome.html.j2", "welcome.subject"));
+        assert!(depends_on("welcome.v2", "welcome.v2.html"));
+        assert!(depends_on("reset/password", "reset/password.txt"));
+        assert!(depends_on("./welcome", "welcome.html"));
+
+        assert!(!depends_on("welcome", "goodbye.html"));
+        assert!(!depends_on("reset/password", "password.txt"));
+        assert!(!depends_on("welcome", "welcome/de.html"));
+    }
+}
@@ -0,0 +1,394 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{
+    engine::{self, Renderable, TemplateEngine},
+    front_matter::FrontMatter,
+    resolver::TemplateResolver,
+};
+use eyre::{Context, Result};
+use jsonschema::JSONSchema;
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Extensions of files that are only used as inline assets, which aren't templates.
+const ASSET_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "svg", "webp"];
+
+/// Width that plaintext bodies are wrapped at when they're generated from HTML.
+const GENERATED_TEXT_WIDTH: usize = 80;
+
+/// Represents a template that was pulled from a [`TemplateResolver`] and compiled. Templates
+/// can either be a single file, or a `.html` and `.txt` pair that share the same name (i.e,
+/// `welcome.html` and `welcome.txt` for the `welcome` template). A `.subject` file with the
+/// same name, or the `subject` in the [front matter][FrontMatter] of the template, can provide
+/// the subject of the email.
+///
+/// Templates are rendered with [Mustache](https://mustache.github.io), unless the `engine` in
+/// their front matter or an extension after the template's own (i.e, `welcome.html.hbs`)
+/// selects [another engine][TemplateEngine].
+#[derive(Debug, Clone)]
+pub struct CompiledTemplate {
+    /// HTML part of this template, if any.
+    pub html: Option<Arc<dyn Renderable>>,
+
+    /// Plaintext part of this template, if any.
+    pub text: Option<Arc<dyn Renderable>>,
+
+    /// Subject of the email, which is used if the request didn't have one.
+    pub subject: Option<Arc<dyn Renderable>>,
+
+    /// JSON Schema that the context of a request needs to match, from a `.schema.json` file
+    /// with the same name or the `schema` in the front matter of the template.
+    pub schema: Option<Arc<JSONSchema>>,
+
+    /// Front matter of the HTML part, or of the plaintext part if the HTML part
+    /// doesn't have any.
+    pub front_matter: FrontMatter,
+}
+
+impl CompiledTemplate {
+    /// Pulls and compiles the template called `name` from a [`TemplateResolver`].
+    ///
+    /// If `name` exists, then it is used as the HTML part if it has a `.html` extension (along
+    /// with a `.txt` file next to it, if it exists) or as the plaintext part otherwise. If it
+    /// doesn't exist, then the `{name}.html` and `{name}.txt` pair is pulled instead, with the
+    /// extension of an engine if there's no pair without one (i.e, `{name}.html.hbs`).
+    pub async fn pull(resolver: &dyn TemplateResolver, name: &str) -> Result<Option<CompiledTemplate>> {
+        let path = Path::new(name);
+
+        // `.subject` and `.schema.json` files are next to the template, without its extensions
+        let (html, text, stem) = match Source::pull(resolver, path.to_path_buf()).await? {
+            Some(source) => {
+                let (base, extension) = match engine::from_extension(path) {
+                    Some((_, base)) => (base, path.extension()),
+                    None => (path.to_path_buf(), None),
+                };
+
+                match engine::is_html(path) {
+                    true => {
+                        let mut text = base.with_extension("txt");
+                        if let Some(extension) = extension {
+                            text = sidecar(&text, &extension.to_string_lossy());
+                        }
+
+                        (
+                            Some(source),
+                            Source::pull(resolver, text).await?,
+                            base.with_extension(""),
+                        )
+                    }
+
+                    false => (None, Some(source), base.with_extension("")),
+                }
+            }
+
+            None => {
+                let mut pair = (None, None);
+                for extension in [None].into_iter().chain(engine::extensions().map(Some)) {
+                    let part = |part: &str| match extension {
+                        Some(extension) => PathBuf::from(format!("{name}.{part}.{extension}")),
+                        None => PathBuf::from(format!("{name}.{part}")),
+                    };
+
+                    pair = (
+                        Source::pull(resolver, part("html")).await?,
+                        Source::pull(resolver, part("txt")).await?,
+                    );
+
+                    if pair.0.is_some() || pair.1.is_some() {
+                        break;
+                    }
+                }
+
+                (pair.0, pair.1, path.to_path_buf())
+            }
+        };
+
+        if html.is_none() && text.is_none() {
+            return Ok(None);
+        }
+
+        let front_matter = [&html, &text]
+            .into_iter()
+            .flatten()
+            .find_map(|source| source.front_matter.clone())
+            .unwrap_or_default();
+
+        let engine = match front_matter.engine {
+            Some(ref engine) => engine::find(engine)
+                .ok_or_else(|| eyre!("template [{name}] uses unknown engine `{engine}` in its front matter"))?,
+
+            None => [&html, &text]
+                .into_iter()
+                .flatten()
+                .find_map(|source| engine::from_extension(&source.path))
+                .map_or_else(engine::default, |(engine, _)| engine),
+        };
+
+        let subject_path = sidecar(&stem, "subject");
+        let subject = match Source::pull(resolver, subject_path.clone()).await? {
+            Some(source) => Some(source.compile(engine)?),
+            None => front_matter
+                .subject
+                .as_deref()
+                .map(|subject| engine.compile(&subject_path, subject))
+                .transpose()
+                .with_context(|| format!("unable to compile subject in front matter of template [{name}]"))?,
+        };
+
+        let schema = match resolver.pull(sidecar(&stem, "schema.json")).await? {
+            Some(contents) => Some(
+                serde_json::from_str(&contents)
+                    .with_context(|| format!("unable to parse JSON schema of template [{name}]"))?,
+            ),
+
+            None => front_matter.schema.clone(),
+        };
+
+        let schema = schema
+            .map(|schema| {
+                JSONSchema::compile(&schema).map_err(|e| eyre!("invalid JSON schema of template [{name}]: {e}"))
+            })
+            .transpose()?;
+
+        Ok(Some(CompiledTemplate {
+            html: html.map(|source| source.compile(engine)).transpose()?,
+            text: text.map(|source| source.compile(engine)).transpose()?,
+            subject,
+            schema: schema.map(Arc::new),
+            front_matter,
+        }))
+    }
+
+    /// Validates `context` against the [schema][CompiledTemplate::schema] of this template,
+    /// returning every value that didn't match as its JSON pointer (i.e, `/user/name`) and
+    /// the reason why.
+    pub fn validate(&self, context: &serde_json::Value) -> Result<(), Vec<(String, String)>> {
+        let Some(ref schema) = self.schema else {
+            return Ok(());
+        };
+
+        schema.validate(context).map_err(|errors| {
+            errors
+                .map(|error| (error.instance_path.to_string(), error.to_string()))
+                .collect()
+        })
+    }
+
+    /// Returns the sorted names of all templates that can be [pulled][CompiledTemplate::pull]
+    /// from the paths that a [`TemplateResolver`] has listed. `.html`, `.txt`, `.subject` and
+    /// `.schema.json` files (with the extension of an engine or not) are listed by the name they
+    /// share, and assets are skipped.
+    pub fn names<I: IntoIterator<Item = String>>(paths: I) -> Vec<String> {
+        paths
+            .into_iter()
+            .filter_map(|path| {
+                if let Some(name) = path.strip_suffix(".schema.json") {
+                    return Some(name.to_owned());
+                }
+
+                // `welcome.html.hbs` is listed as `welcome`, like `welcome.html`
+                let path = match engine::from_extension(Path::new(&path)) {
+                    Some((_, base)) if base.extension().map_or(false, |ext| ext == "html" || ext == "txt") => {
+                        base.to_string_lossy().into_owned()
+                    }
+
+                    _ => path,
+                };
+
+                match path.rsplit_once('.') {
+                    Some((name, "html" | "txt" | "subject")) => Some(name.to_owned()),
+                    Some((_, ext)) if ASSET_EXTENSIONS.iter().any(|asset| asset.eq_ignore_ascii_case(ext)) => None,
+                    _ => Some(path),
+                }
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Renders all parts of this template with the given context.
+    pub fn render(&self, context: &serde_json::Value) -> Result<Body> {
+        Ok(Body {
+            html: self
+                .html
+                .as_ref()
+                .map(|template| template.render(context))
+                .transpose()?,
+            text: self
+                .text
+                .as_ref()
+                .map(|template| template.render(context))
+                .transpose()?,
+            subject: self
+                .subject
+                .as_ref()
+                .map(|template| template.render(context))
+                .transpose()?,
+        })
+    }
+}
+
+/// Represents the body of an email, which can have a HTML part, a plaintext part or both.
+#[derive(Debug, Clone, Default)]
+pub struct Body {
+    pub html: Option<String>,
+    pub text: Option<String>,
+
+    /// Subject that was rendered from the template, if it has one.
+    pub subject: Option<String>,
+}
+
+impl Body {
+    /// Returns the plaintext part of this body. If there is only a HTML part, then it'll be
+    /// generated from the HTML.
+    pub fn text_or_generated(&self) -> Option<String> {
+        match (&self.text, &self.html) {
+            (Some(text), _) => Some(text.clone()),
+            (None, Some(html)) => Some(html2text::from_read(html.as_bytes(), GENERATED_TEXT_WIDTH)),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Returns the path of the `ext` file that belongs to the template at `stem`, i.e, `welcome.subject`.
+fn sidecar(stem: &Path, ext: &str) -> PathBuf {
+    let mut path = stem.as_os_str().to_owned();
+    path.push(".");
+    path.push(ext);
+
+    PathBuf::from(path)
+}
+
+/// Represents the source of a template that was pulled, without its front matter.
+struct Source {
+    path: PathBuf,
+    contents: String,
+    front_matter: Option<FrontMatter>,
+}
+
+impl Source {
+    /// Pulls the template at `path` and splits its front matter from it.
+    async fn pull(resolver: &dyn TemplateResolver, path: PathBuf) -> Result<Option<Source>> {
+        let Some(contents) = resolver.pull(path.clone()).await? else {
+            return Ok(None);
+        };
+
+        let (front_matter, contents) = FrontMatter::split(&contents)
+            .with_context(|| format!("unable to parse front matter of template [{}]", path.display()))?;
+
+        Ok(Some(Source {
+            contents: contents.to_owned(),
+            front_matter,
+            path,
+        }))
+    }
+
+    fn compile(&self, engine: &dyn TemplateEngine) -> Result<Arc<dyn Renderable>> {
+        engine.compile(&self.path, &self.contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompiledTemplate;
+    use crate::templates::resolver::TemplateResolver;
+    use eyre::Result;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    struct TestTemplateResolver;
+
+    #[async_trait]
+    impl TemplateResolver for TestTemplateResolver {
+        async fn pull(&self, path: PathBuf) -> Result<Option<String>> {
+            Ok(match path.to_str() {
+                Some("welcome.html") => Some(String::from(
+                    "---\nschema:\n  type: object\n  required: [name]\n---\n<p>Hello, {{name}}!</p>",
+                )),
+                Some("reset.txt") => Some(String::from("Reset your password, {{user.name}}")),
+                Some("invoice.html.hbs") => Some(String::from("{{#each items}}<li>{{@index}}: {{this}}</li>{{/each}}")),
+                Some("invoice.subject") => {
+                    Some(String::from("{{#if (gt (len items) 1)}}Invoices{{else}}Invoice{{/if}}"))
+                }
+                Some("reset.schema.json") => Some(String::from(
+                    r#"{"properties": {"user": {"properties": {"name": {"type": "string"}}}}}"#,
+                )),
+
+                _ => None,
+            })
+        }
+
+        async fn list(&self) -> Result<Vec<String>> {
+            Ok(vec![])
+        }
+    }
+
+    #[tokio::test]
+    async fn engine_from_extension() {
+        let invoice = CompiledTemplate::pull(&TestTemplateResolver, "invoice")
+            .await
+            .unwrap()
+            .unwrap();
+
+        let body = invoice.render(&json!({ "items": ["<a>", "b"] })).unwrap();
+        assert_eq!(body.html.as_deref(), Some("<li>0: &lt;a&gt;</li><li>1: b</li>"));
+        assert_eq!(body.subject.as_deref(), Some("Invoices"));
+        assert!(body.text.is_none());
+    }
+
+    #[tokio::test]
+    async fn validate_context_against_schema() {
+        let welcome = CompiledTemplate::pull(&TestTemplateResolver, "welcome")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(welcome.validate(&json!({ "name": "Noel" })).is_ok());
+        let errors = welcome.validate(&json!({})).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "");
+
+        let reset = CompiledTemplate::pull(&TestTemplateResolver, "reset")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(reset.validate(&json!({ "user": { "name": "Noel" } })).is_ok());
+        let errors = reset.validate(&json!({ "user": { "name": 1 } })).unwrap_err();
+        assert_eq!(errors[0].0, "/user/name");
+    }
+
+    #[test]
+    fn names_collapse_html_and_text_pairs() {
+        let paths = [
+            "welcome.html",
+            "welcome.txt",
+            "welcome.schema.json",
+            "invoice.html.hbs",
+            "invoice.txt.hbs",
+            "logo.png",
+            "reset/password.txt",
+            "plain.tmpl",
+        ];
+        assert_eq!(
+            CompiledTemplate::names(paths.map(String::from)),
+            vec!["invoice", "plain.tmpl", "reset/password", "welcome"]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod handlebars;
+mod minijinja;
+mod mustache;
+
+pub use self::{handlebars::HandlebarsEngine, minijinja::MiniJinjaEngine, mustache::MustacheEngine};
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, FixedOffset, TimeZone, Utc,
+};
+use eyre::Result;
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Format that dates are formatted with if the template didn't specify one.
+pub(crate) const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// All engines that templates can be rendered with. The first engine is used when a template
+/// doesn't select one.
+static ENGINES: &[&dyn TemplateEngine] = &[&MustacheEngine, &MiniJinjaEngine, &HandlebarsEngine];
+
+/// Represents an engine that can compile and render templates, like Mustache or Handlebars.
+pub trait TemplateEngine: Send + Sync {
+    /// Name of this engine, which templates can select with the `engine` in their front matter.
+    fn name(&self) -> &'static str;
+
+    /// File extensions (without the leading dot) that select this engine when they come after
+    /// the extension of a template, i.e, `hbs` for `welcome.html.hbs`.
+    fn extensions(&self) -> &'static [&'static str];
+
+    /// Compiles `source`, which was pulled from `path`. Values are HTML-escaped when `path`
+    /// is a HTML template.
+    fn compile(&self, path: &Path, source: &str) -> Result<Arc<dyn Renderable>>;
+}
+
+/// Represents a template that was compiled by a [`TemplateEngine`].
+pub trait Renderable: Debug + Send + Sync {
+    /// Renders this template with the given context.
+    fn render(&self, context: &serde_json::Value) -> Result<String>;
+}
+
+/// Returns the default engine, which is Mustache.
+pub fn default() -> &'static dyn TemplateEngine {
+    ENGINES[0]
+}
+
+/// Returns the engine called `name`.
+pub fn find(name: &str) -> Option<&'static dyn TemplateEngine> {
+    ENGINES
+        .iter()
+        .copied()
+        .find(|engine| engine.name().eq_ignore_ascii_case(name))
+}
+
+/// Returns the engine that the extension of `path` selects, along with `path` without it.
+pub fn from_extension(path: &Path) -> Option<(&'static dyn TemplateEngine, PathBuf)> {
+    let extension = path.extension()?.to_str()?;
+    let engine = ENGINES.iter().copied().find(|engine| {
+        engine
+            .extensions()
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(extension))
+    })?;
+
+    Some((engine, path.with_extension("")))
+}
+
+/// Returns the extensions of all engines.
+pub fn extensions() -> impl Iterator<Item = &'static str> {
+    ENGINES.iter().flat_map(|engine| engine.extensions().iter().copied())
+}
+
+/// Returns whether `path` is a HTML template, ignoring the extension of an engine.
+pub(crate) fn is_html(path: &Path) -> bool {
+    let path = from_extension(path).map_or_else(|| path.to_path_buf(), |(_, path)| path);
+    matches!(path.extension().and_then(|ext| ext.to_str()), Some("html" | "htm"))
+}
+
+/// Formats `value`, which is either a RFC 3339 date or a Unix timestamp in seconds, with a
+/// `strftime`-like `format`. Returns `None` if either of them is invalid.
+pub(crate) fn format_date(value: &serde_json::Value, format: &str) -> Option<String> {
+    let date: DateTime<FixedOffset> = match value {
+        serde_json::Value::String(date) => DateTime::parse_from_rfc3339(date).ok()?,
+        serde_json::Value::Number(seconds) => Utc.timestamp_opt(seconds.as_i64()?, 0).single()?.fixed_offset(),
+        _ => return None,
+    };
+
+    // chrono panics when a format with invalid specifiers is displayed
+    let items = StrftimeItems::new(format).collect::<Vec<_>>();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return None;
+    }
+
+    Some(date.format_with_items(items.into_iter()).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find, from_extension};
+    use serde_json::json;
+    use std::path::Path;
+
+    #[test]
+    fn render_with_every_engine() {
+        let context = json!({
+            "name": "<Noel>",
+            "items": ["charts", "repositories"],
+            "count": 2,
+            "created_at": "2023-10-15T12:00:00Z",
+        });
+
+        let templates = [
+            ("mustache", "{{name}}: {{#items}}{{.}} {{/items}}"),
+            (
+                "minijinja",
+                "{{ name }}: {% for item in items %}{{ loop.index }}.{{ item }} {% endfor %}{% if count > 1 %}many{% endif %} {{ created_at | date(\"%B %d, %Y\") }}",
+            ),
+            (
+                "handlebars",
+                "{{name}}: {{#each items}}{{@index}}.{{this}} {{/each}}{{#if (gt count 1)}}many{{/if}} {{date created_at \"%B %d, %Y\"}}",
+            ),
+        ];
+
+        let rendered = templates.map(|(engine, source)| {
+            let engine = find(engine).unwrap();
+            let html = engine.compile(Path::new("welcome.html"), source).unwrap();
+            let text = engine.compile(Path::new("welcome.txt"), source).unwrap();
+
+            (html.render(&context).unwrap(), text.render(&context).unwrap())
+        });
+
+        assert_eq!(
+            rendered[0],
+            (
+                String::from("&lt;Noel&gt;: charts repositories "),
+                String::from("<Noel>: charts repositories ")
+            )
+        );
+        assert_eq!(
+            rendered[1],
+            (
+                String::from("&lt;Noel&gt;: 1.charts 2.repositories many October 15, 2023"),
+                String::from("<Noel>: 1.charts 2.repositories many October 15, 2023")
+            )
+        );
+
+        assert_eq!(
+            rendered[2],
+            (
+                String::from("&lt;Noel&gt;: 0.charts 1.repositories many October 15, 2023"),
+                String::from("<Noel>: 0.charts 1.repositories many October 15, 2023")
+            )
+        );
+    }
+
+    #[test]
+    fn subjects_are_not_escaped() {
+        let context = json!({ "name": "Noel's \"charts\" & <co>" });
+        for (engine, source) in [
+            (
+                "mustache",
+                "Welcome, {{name}}{{! comment }}{{#name}}!{{/name}} {{{name}}} {{&name}}",
+            ),
+            ("minijinja", "Welcome, {{ name }}! {{ name }} {{ name }}"),
+            ("handlebars", "Welcome, {{name}}! {{{name}}} {{name}}"),
+        ] {
+            let subject = find(engine)
+                .unwrap()
+                .compile(Path::new("welcome.subject"), source)
+                .unwrap();
+
+            assert_eq!(
+                subject.render(&context).unwrap(),
+                "Welcome, Noel's \"charts\" & <co>! Noel's \"charts\" & <co> Noel's \"charts\" & <co>",
+                "{engine}"
+            );
+        }
+    }
+
+    #[test]
+    fn engines_from_extensions() {
+        let (engine, base) = from_extension(Path::new("welcome.html.hbs")).unwrap();
+        assert_eq!(engine.name(), "handlebars");
+        assert_eq!(base, Path::new("welcome.html"));
+
+        assert_eq!(from_extension(Path::new("welcome.j2")).unwrap().0.name(), "minijinja");
+        assert!(from_extension(Path::new("welcome.html")).is_none());
+    }
+}
@@ -0,0 +1,81 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{format_date, is_html, Renderable, TemplateEngine, DEFAULT_DATE_FORMAT};
+use eyre::{Context as _, Result};
+use handlebars::{
+    html_escape, no_escape, Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError,
+};
+use std::{path::Path, sync::Arc};
+
+/// Name that the template is registered as.
+const TEMPLATE_NAME: &str = "template";
+
+/// Renders templates with [Handlebars](https://handlebarsjs.com), which supports `@index` in
+/// loops and comparison helpers like `eq` and `gt`. Dates can be formatted with the `date`
+/// helper, i.e, `{{date created_at "%B %d, %Y"}}`.
+#[derive(Debug, Clone, Copy)]
+pub struct HandlebarsEngine;
+
+impl TemplateEngine for HandlebarsEngine {
+    fn name(&self) -> &'static str {
+        "handlebars"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["hbs", "handlebars"]
+    }
+
+    fn compile(&self, path: &Path, source: &str) -> Result<Arc<dyn Renderable>> {
+        let mut registry = Handlebars::new();
+        match is_html(path) {
+            true => registry.register_escape_fn(html_escape),
+            false => registry.register_escape_fn(no_escape),
+        }
+
+        registry.register_helper("date", Box::new(date));
+        registry
+            .register_template_string(TEMPLATE_NAME, source)
+            .with_context(|| format!("unable to compile handlebars template [{}]", path.display()))?;
+
+        Ok(Arc::new(HandlebarsTemplate(registry)))
+    }
+}
+
+#[derive(Debug)]
+struct HandlebarsTemplate(Handlebars<'static>);
+
+impl Renderable for HandlebarsTemplate {
+    fn render(&self, context: &serde_json::Value) -> Result<String> {
+        Ok(self.0.render(TEMPLATE_NAME, context)?)
+    }
+}
+
+fn date(helper: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    let value = helper
+        .param(0)
+        .ok_or_else(|| RenderError::new("`date` expects a date to format"))?;
+
+    let format = helper
+        .param(1)
+        .and_then(|format| format.value().as_str())
+        .unwrap_or(DEFAULT_DATE_FORMAT);
+
+    let formatted = format_date(value.value(), format)
+        .ok_or_else(|| RenderError::new("`date` expects a RFC 3339 date or Unix timestamp, and a valid format"))?;
+
+    out.write(&formatted)?;
+    Ok(())
+}
@@ -0,0 +1,72 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{format_date, is_html, Renderable, TemplateEngine, DEFAULT_DATE_FORMAT};
+use eyre::{Context, Result};
+use minijinja::{AutoEscape, Environment, Error, ErrorKind, Value};
+use std::{path::Path, sync::Arc};
+
+/// Name that the template is added to its environment as.
+const TEMPLATE_NAME: &str = "template";
+
+/// Renders templates with [MiniJinja](https://docs.rs/minijinja), which supports most of Jinja2's
+/// syntax, like loops with `loop.index`, comparisons and filters. Dates can be formatted with
+/// the `date` filter, i.e, `{{ created_at | date("%B %d, %Y") }}`.
+#[derive(Debug, Clone, Copy)]
+pub struct MiniJinjaEngine;
+
+impl TemplateEngine for MiniJinjaEngine {
+    fn name(&self) -> &'static str {
+        "minijinja"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["j2", "jinja"]
+    }
+
+    fn compile(&self, path: &Path, source: &str) -> Result<Arc<dyn Renderable>> {
+        let html = is_html(path);
+        let mut env = Environment::new();
+        env.set_auto_escape_callback(move |_| match html {
+            true => AutoEscape::Html,
+            false => AutoEscape::None,
+        });
+
+        env.add_filter("date", date);
+        env.add_template_owned(TEMPLATE_NAME, source.to_owned())
+            .with_context(|| format!("unable to compile minijinja template [{}]", path.display()))?;
+
+        Ok(Arc::new(MiniJinjaTemplate(env)))
+    }
+}
+
+#[derive(Debug)]
+struct MiniJinjaTemplate(Environment<'static>);
+
+impl Renderable for MiniJinjaTemplate {
+    fn render(&self, context: &serde_json::Value) -> Result<String> {
+        Ok(self.0.get_template(TEMPLATE_NAME)?.render(context)?)
+    }
+}
+
+fn date(value: Value, format: Option<String>) -> Result<String, Error> {
+    let value = serde_json::to_value(&value).map_err(|e| Error::new(ErrorKind::InvalidOperation, e.to_string()))?;
+    format_date(&value, format.as_deref().unwrap_or(DEFAULT_DATE_FORMAT)).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidOperation,
+            "expected a RFC 3339 date or Unix timestamp, and a valid format",
+        )
+    })
+}
@@ -0,0 +1,76 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{is_html, Renderable, TemplateEngine};
+use eyre::{Context, Result};
+use mustache::Template;
+use std::{borrow::Cow, path::Path, sync::Arc};
+
+/// Renders templates with [Mustache](https://mustache.github.io), which is the default engine.
+#[derive(Debug, Clone, Copy)]
+pub struct MustacheEngine;
+
+impl TemplateEngine for MustacheEngine {
+    fn name(&self) -> &'static str {
+        "mustache"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["mustache"]
+    }
+
+    fn compile(&self, path: &Path, source: &str) -> Result<Arc<dyn Renderable>> {
+        let source = match is_html(path) {
+            true => Cow::Borrowed(source),
+            false => Cow::Owned(unescaped(source)),
+        };
+
+        mustache::compile_str(&source)
+            .map(|template| Arc::new(MustacheTemplate(template)) as Arc<dyn Renderable>)
+            .with_context(|| format!("unable to compile mustache template [{}]", path.display()))
+    }
+}
+
+/// Turns every `{{name}}` tag in `source` into an unescaped `{{&name}}` tag, since the
+/// mustache crate always escapes values for HTML. Tags after the delimiters were changed
+/// (i.e, `{{=<% %>=}}`) are still escaped.
+fn unescaped(source: &str) -> String {
+    let mut output = String::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        let (text, tag) = rest.split_at(start + 2);
+        output.push_str(text);
+        rest = tag;
+
+        match tag.chars().next() {
+            Some('=') => break,
+            Some('{' | '&' | '#' | '^' | '/' | '!' | '>') | None => {}
+            Some(_) => output.push('&'),
+        }
+    }
+
+    output.push_str(rest);
+    output
+}
+
+#[derive(Debug)]
+struct MustacheTemplate(Template);
+
+impl Renderable for MustacheTemplate {
+    fn render(&self, context: &serde_json::Value) -> Result<String> {
+        let data = mustache::to_data(context)?;
+        Ok(self.0.render_data_to_string(&data)?)
+    }
+}
@@ -0,0 +1,127 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use eyre::{Context, Result};
+use lettre::message::Mailbox;
+use serde::Deserialize;
+
+/// Metadata of a template that is declared in a YAML block at the top of it, which is
+/// delimited by `---` lines:
+///
+/// ```text
+/// ---
+/// subject: Welcome to charted, {{name}}!
+/// from: charted <noreply@charted.dev>
+/// description: Sent after a user signs up
+/// tags: [onboarding]
+/// ---
+/// <p>Hello, {{name}}!</p>
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FrontMatter {
+    /// Subject of the email, which is rendered with the same context as the template. It's
+    /// only used if the request didn't have a subject.
+    #[serde(default)]
+    pub subject: Option<String>,
+
+    /// Sender of the email, instead of `config.smtp.from_addr`.
+    #[serde(default)]
+    pub from: Option<Mailbox>,
+
+    /// Address that replies are sent to, if the request didn't have any `reply_to` recipients.
+    #[serde(default)]
+    pub reply_to: Option<Mailbox>,
+
+    /// Human-readable description of what this template is used for.
+    #[serde(default)]
+    pub description: Option<String>,
+
+    /// Name of the [engine](crate::templates::engine::TemplateEngine) to render the template
+    /// with, i.e, `handlebars` or `minijinja`. Default is `mustache`.
+    #[serde(default)]
+    pub engine: Option<String>,
+
+    /// Arbitrary tags to organize templates with.
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// JSON Schema that the context of a request needs to match, if the template doesn't
+    /// have a `.schema.json` file.
+    #[serde(default)]
+    pub schema: Option<serde_json::Value>,
+}
+
+impl FrontMatter {
+    /// Splits `contents` into its front matter, if it has any, and the rest of the template.
+    pub fn split(contents: &str) -> Result<(Option<FrontMatter>, &str)> {
+        let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+        let Some(rest) = strip_delimiter(contents) else {
+            return Ok((None, contents));
+        };
+
+        let mut offset = 0;
+        for line in rest.split_inclusive('\n') {
+            if let Some(template) = strip_delimiter(&rest[offset..]) {
+                let front_matter = match rest[..offset].trim() {
+                    "" => FrontMatter::default(),
+                    yaml => serde_yaml::from_str(yaml).context("unable to parse front matter")?,
+                };
+
+                return Ok((Some(front_matter), template));
+            }
+
+            offset += line.len();
+        }
+
+        Err(eyre!("front matter is missing its closing `---` line"))
+    }
+}
+
+/// Strips a `---` line from the start of `contents`, returning what comes after it.
+fn strip_delimiter(contents: &str) -> Option<&str> {
+    let rest = contents.strip_prefix("---")?;
+    if rest.is_empty() {
+        return Some(rest);
+    }
+
+    rest.strip_prefix("\r\n").or_else(|| rest.strip_prefix('\n'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrontMatter;
+
+    #[test]
+    fn split_front_matter() {
+        let (front_matter, template) = FrontMatter::split(
+            "---\r\nsubject: Hello, {{name}}!\r\nfrom: charted <noreply@charted.dev>\r\ntags: [onboarding]\r\n---\r\n<p>Hi!</p>\n---\n",
+        )
+        .unwrap();
+
+        let front_matter = front_matter.unwrap();
+        assert_eq!(front_matter.subject.as_deref(), Some("Hello, {{name}}!"));
+        assert_eq!(front_matter.from.unwrap().to_string(), "charted <noreply@charted.dev>");
+        assert_eq!(front_matter.tags, vec!["onboarding"]);
+        assert_eq!(template, "<p>Hi!</p>\n---\n");
+
+        let (front_matter, template) = FrontMatter::split("<p>---</p>").unwrap();
+        assert!(front_matter.is_none());
+        assert_eq!(template, "<p>---</p>");
+
+        assert!(FrontMatter::split("---\nsubject: Hello\n").is_err());
+        assert!(FrontMatter::split("---\nsubjetc: Hello\n---\n").is_err());
+    }
+}
@@ -14,11 +14,18 @@
 // limitations under the License.
 
 use eyre::Result;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tokio::{
+    fs,
+    sync::{broadcast, watch},
+};
 
+pub mod composite;
+pub mod email_template;
 pub mod filesystem;
 pub mod git;
 pub mod kubernetes;
+pub mod s3;
 
 /// Represents a trait that allows to resolve templates from any canonical source.
 #[async_trait]
@@ -39,4 +46,105 @@ pub trait TemplateResolver: Send + Sync {
     /// For the Kubernetes resolver, slashes are not allowed expect in first 2 characters,
     /// which will be stripped if found.
     async fn pull(&self, path: PathBuf) -> Result<Option<String>>;
+
+    /// Pulls a `path` as raw bytes rather than UTF-8 text, which is used for assets
+    /// that are embedded in templates, like images.
+    async fn pull_bytes(&self, path: PathBuf) -> Result<Option<Vec<u8>>> {
+        self.pull(path).await.map(|contents| contents.map(String::into_bytes))
+    }
+
+    /// Returns a receiver that is updated whenever this resolver becomes healthy or unhealthy,
+    /// i.e, when the Git resolver can't verify the latest commit. Resolvers that can't become
+    /// unhealthy return `None`.
+    fn healthy(&self) -> Option<watch::Receiver<bool>> {
+        None
+    }
+
+    /// Returns a receiver of the files that changed in this resolver's source, so templates that
+    /// were compiled from them can be evicted from the [`TemplateCache`](crate::templates::cache::TemplateCache).
+    /// Resolvers that can't detect changes return `None`, so their templates are only evicted
+    /// once they expire after `cache.ttl` seconds.
+    fn changes(&self) -> Option<broadcast::Receiver<Change>> {
+        None
+    }
+
+    /// Lists the paths of every file that this resolver can [`pull`][TemplateResolver::pull],
+    /// which can be passed back into `pull` as-is.
+    async fn list(&self) -> Result<Vec<String>>;
+}
+
+/// Represents a change in the source of a [`TemplateResolver`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// A single file changed, as a `/`-delimited path that [`pull`][TemplateResolver::pull] accepts.
+    Path(String),
+
+    /// Anything could have changed, i.e, when a ConfigMap volume was remounted.
+    All,
+}
+
+/// Recursively lists all files in `root` as `/`-delimited paths relative to `root`. Hidden
+/// files and directories (i.e, `.git`) are skipped.
+pub(crate) async fn walk(root: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut directories = vec![root.to_path_buf()];
+
+    while let Some(directory) = directories.pop() {
+        let mut entries = fs::read_dir(&directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                directories.push(path);
+                continue;
+            }
+
+            let relative = path.strip_prefix(root)?;
+            files.push(
+                relative
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/"),
+            );
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::TemplateResolver;
+    use eyre::Result;
+    use std::{
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    /// Resolves every `.txt` path and counts how many times it was pulled.
+    pub(crate) struct CountingTemplateResolver(pub(crate) Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl TemplateResolver for CountingTemplateResolver {
+        async fn pull(&self, path: PathBuf) -> Result<Option<String>> {
+            if path.extension().map_or(false, |ext| ext == "txt") {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                return Ok(Some(String::from("Hello, {{name}}!")));
+            }
+
+            Ok(None)
+        }
+
+        async fn list(&self) -> Result<Vec<String>> {
+            Ok(vec![])
+        }
+    }
 }
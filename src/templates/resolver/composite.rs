@@ -0,0 +1,174 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{Change, TemplateResolver};
+use eyre::Result;
+use futures::future::select_all;
+use std::path::PathBuf;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    watch,
+};
+
+/// Represents a [`TemplateResolver`] that tries a list of resolvers in order, and uses the
+/// first one that has the template. This allows overriding templates from one source (i.e,
+/// ConfigMaps) and falling back to defaults from another (i.e, a Git repository).
+pub struct CompositeTemplateResolver(Vec<Box<dyn TemplateResolver>>);
+
+impl CompositeTemplateResolver {
+    pub fn new(resolvers: Vec<Box<dyn TemplateResolver>>) -> CompositeTemplateResolver {
+        CompositeTemplateResolver(resolvers)
+    }
+}
+
+#[async_trait]
+impl TemplateResolver for CompositeTemplateResolver {
+    async fn init(&self) -> Result<()> {
+        for resolver in &self.0 {
+            resolver.init().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn pull(&self, path: PathBuf) -> Result<Option<String>> {
+        for resolver in &self.0 {
+            if let Some(contents) = resolver.pull(path.clone()).await? {
+                return Ok(Some(contents));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn pull_bytes(&self, path: PathBuf) -> Result<Option<Vec<u8>>> {
+        for resolver in &self.0 {
+            if let Some(contents) = resolver.pull_bytes(path.clone()).await? {
+                return Ok(Some(contents));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut paths = Vec::new();
+        for resolver in &self.0 {
+            paths.extend(resolver.list().await?);
+        }
+
+        paths.sort();
+        paths.dedup();
+        Ok(paths)
+    }
+
+    /// The composite resolver is healthy while all of its resolvers are.
+    fn healthy(&self) -> Option<watch::Receiver<bool>> {
+        let mut receivers = self
+            .0
+            .iter()
+            .filter_map(|resolver| resolver.healthy())
+            .collect::<Vec<_>>();
+        if receivers.len() <= 1 {
+            return receivers.pop();
+        }
+
+        let (sender, receiver) = watch::channel(receivers.iter().all(|healthy| *healthy.borrow()));
+        tokio::spawn(async move {
+            loop {
+                let (changed, ..) = select_all(receivers.iter_mut().map(|healthy| Box::pin(healthy.changed()))).await;
+                if changed.is_err() {
+                    break;
+                }
+
+                sender.send_replace(receivers.iter().all(|healthy| *healthy.borrow()));
+            }
+        });
+
+        Some(receiver)
+    }
+
+    /// Changes of all resolvers are merged, since a template that changed in one resolver
+    /// might shadow the same template in another.
+    fn changes(&self) -> Option<broadcast::Receiver<Change>> {
+        let mut receivers = self
+            .0
+            .iter()
+            .filter_map(|resolver| resolver.changes())
+            .collect::<Vec<_>>();
+        if receivers.len() <= 1 {
+            return receivers.pop();
+        }
+
+        let (sender, receiver) = broadcast::channel(128);
+        for mut changes in receivers {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                loop {
+                    let change = match changes.recv().await {
+                        Ok(change) => change,
+                        Err(RecvError::Lagged(_)) => Change::All,
+                        Err(RecvError::Closed) => break,
+                    };
+
+                    if sender.send(change).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        Some(receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompositeTemplateResolver;
+    use crate::templates::resolver::TemplateResolver;
+    use eyre::Result;
+    use std::{collections::HashMap, path::PathBuf};
+
+    struct StaticTemplateResolver(HashMap<&'static str, &'static str>);
+
+    #[async_trait]
+    impl TemplateResolver for StaticTemplateResolver {
+        async fn pull(&self, path: PathBuf) -> Result<Option<String>> {
+            Ok(path.to_str().and_then(|path| self.0.get(path)).map(|s| s.to_string()))
+        }
+
+        async fn list(&self) -> Result<Vec<String>> {
+            Ok(self.0.keys().map(|s| s.to_string()).collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn pull_from_first_resolver_that_has_the_template() {
+        let overrides = StaticTemplateResolver(HashMap::from([("welcome.html", "override")]));
+        let defaults = StaticTemplateResolver(HashMap::from([("welcome.html", "default"), ("reset.html", "default")]));
+        let resolver = CompositeTemplateResolver::new(vec![Box::new(overrides), Box::new(defaults)]);
+
+        assert_eq!(
+            resolver.pull("welcome.html".into()).await.unwrap().as_deref(),
+            Some("override")
+        );
+        assert_eq!(
+            resolver.pull("reset.html".into()).await.unwrap().as_deref(),
+            Some("default")
+        );
+        assert_eq!(resolver.pull("missing.html".into()).await.unwrap(), None);
+        assert_eq!(resolver.list().await.unwrap(), vec!["reset.html", "welcome.html"]);
+    }
+}
@@ -0,0 +1,328 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{kubernetes::reflect, Change, TemplateResolver};
+use crate::{config::FromEnv, var};
+use eyre::Result;
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{CustomResourceDefinition, ValidationRule};
+use kube::{
+    runtime::reflector::{ObjectRef, Store},
+    Api, Client, CustomResource, CustomResourceExt,
+};
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Schema, SchemaObject},
+    JsonSchema,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug, Formatter},
+    path::PathBuf,
+};
+use tokio::sync::broadcast;
+use tracing::{info, instrument};
+
+/// Represents an email template that is managed as a Kubernetes resource. Templates are
+/// referenced by the name of the resource, and `{name}/{locale}` for a locale variant.
+#[derive(CustomResource, Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[kube(
+    group = "charted.noelware.org",
+    version = "v1alpha1",
+    kind = "EmailTemplate",
+    shortname = "emailtpl",
+    namespaced
+)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailTemplateSpec {
+    /// Mustache template of the subject, which is used if the request doesn't have one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+
+    /// Mustache template of the HTML part.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+
+    /// Mustache template of the plaintext part. If this isn't set, then it's generated
+    /// from the HTML part.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+
+    /// Variants of this template for other locales, keyed by the locale (i.e, `de` or `pt-BR`).
+    /// Parts that a variant doesn't set are used from this template instead.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub locales: BTreeMap<String, EmailTemplateVariant>,
+
+    /// JSON Schema that the context of a request needs to match to use this template.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "any_object")]
+    pub context_schema: Option<serde_json::Value>,
+}
+
+/// Variant of an [`EmailTemplate`] for a specific locale.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct EmailTemplateVariant {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+/// Schema for arbitrary JSON objects, which Kubernetes doesn't prune unknown fields from.
+fn any_object(_: &mut SchemaGenerator) -> Schema {
+    let mut schema = SchemaObject {
+        instance_type: Some(InstanceType::Object.into()),
+        ..Default::default()
+    };
+
+    schema
+        .extensions
+        .insert("x-kubernetes-preserve-unknown-fields".into(), true.into());
+
+    Schema::Object(schema)
+}
+
+impl EmailTemplate {
+    /// Returns the [`CustomResourceDefinition`] to install in the cluster, which also validates that
+    /// every template and locale variant has a HTML or plaintext part when it is admitted.
+    pub fn definition() -> CustomResourceDefinition {
+        let mut crd = EmailTemplate::crd();
+        for version in crd.spec.versions.iter_mut() {
+            let Some(spec) = version
+                .schema
+                .as_mut()
+                .and_then(|schema| schema.open_api_v3_schema.as_mut())
+                .and_then(|schema| schema.properties.as_mut())
+                .and_then(|properties| properties.get_mut("spec"))
+            else {
+                continue;
+            };
+
+            spec.x_kubernetes_validations = Some(vec![
+                ValidationRule {
+                    rule: String::from("has(self.html) || has(self.text)"),
+                    message: Some(String::from("template needs a `html` or `text` part")),
+                },
+                ValidationRule {
+                    rule: String::from(
+                        "!has(self.locales) || self.locales.all(l, has(self.locales[l].html) || has(self.locales[l].text) || has(self.locales[l].subject))",
+                    ),
+                    message: Some(String::from("locale variants need to override at least one part")),
+                },
+            ]);
+        }
+
+        crd
+    }
+}
+
+/// Configuration for the [`EmailTemplateResolver`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    /// Namespace to find `EmailTemplate` resources in. If this isn't set, then the namespace
+    /// of the Kubernetes configuration is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+
+    /// Label selector that `EmailTemplate` resources need to match to be used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label_selector: Option<String>,
+}
+
+impl FromEnv for Config {
+    type Output = Config;
+
+    fn from_env() -> Self::Output {
+        Config {
+            namespace: var!("EMAILS_TEMPLATES_KUBERNETES_NAMESPACE", is_optional: true),
+            label_selector: var!("EMAILS_TEMPLATES_KUBERNETES_LABEL_SELECTOR", is_optional: true),
+        }
+    }
+}
+
+/// Represents a [`TemplateResolver`] that resolves templates from [`EmailTemplate`] resources, which
+/// are watched and kept in an in-memory cache.
+///
+/// The parts of a template are pulled as `{name}.html`, `{name}.txt` and `{name}.subject`, so the
+/// `welcome` template can be used as-is, and `welcome/de` uses its `de` locale variant.
+#[derive(Clone)]
+pub struct EmailTemplateResolver {
+    store: Store<EmailTemplate>,
+    namespace: String,
+    changes: broadcast::Sender<Change>,
+}
+
+impl Debug for EmailTemplateResolver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmailTemplateResolver")
+            .field("namespace", &self.namespace)
+            .field("cached", &self.store.len())
+            .finish()
+    }
+}
+
+impl EmailTemplateResolver {
+    /// Creates a new [`EmailTemplateResolver`] instance.
+    pub async fn new(config: Config) -> Result<EmailTemplateResolver> {
+        let client = Client::try_default().await?;
+        let namespace = config
+            .namespace
+            .unwrap_or_else(|| client.default_namespace().to_owned());
+
+        info!(%namespace, "watching EmailTemplates in namespace");
+        let changes = broadcast::channel(16).0;
+        Ok(EmailTemplateResolver {
+            store: reflect(
+                Api::namespaced(client, &namespace),
+                config.label_selector.as_deref(),
+                changes.clone(),
+            ),
+            namespace,
+            changes,
+        })
+    }
+}
+
+#[async_trait]
+impl TemplateResolver for EmailTemplateResolver {
+    async fn init(&self) -> Result<()> {
+        self.store.wait_until_ready().await?;
+        info!(templates = self.store.len(), "cached EmailTemplates");
+
+        Ok(())
+    }
+
+    #[instrument(
+        name = "emails.resolvers.email_template.pull",
+        skip_all,
+        fields(path = %path.display())
+    )]
+    async fn pull(&self, path: PathBuf) -> Result<Option<String>> {
+        let path = path.strip_prefix("./").unwrap_or(&path);
+        let Some(s) = path.to_str() else {
+            return Err(eyre!("received invalid utf-8 path"));
+        };
+
+        let Some((template, part)) = s
+            .strip_suffix(".schema.json")
+            .map(|template| (template, "schema.json"))
+            .or_else(|| s.rsplit_once('.'))
+        else {
+            return Ok(None);
+        };
+
+        let (name, locale) = match template.split_once('/') {
+            Some((name, locale)) => (name, Some(locale)),
+            None => (template, None),
+        };
+
+        let Some(resource) = self.store.get(&ObjectRef::new(name).within(&self.namespace)) else {
+            return Ok(None);
+        };
+
+        let variant = match locale {
+            Some(locale) => match resource.spec.locales.get(locale) {
+                Some(variant) => Some(variant),
+                None => return Ok(None),
+            },
+
+            None => None,
+        };
+
+        let spec = &resource.spec;
+        Ok(match part {
+            "html" => variant.and_then(|v| v.html.as_ref()).or(spec.html.as_ref()).cloned(),
+            "txt" => variant.and_then(|v| v.text.as_ref()).or(spec.text.as_ref()).cloned(),
+            "subject" => variant
+                .and_then(|v| v.subject.as_ref())
+                .or(spec.subject.as_ref())
+                .cloned(),
+
+            // the schema is shared by all locales
+            "schema.json" => spec.context_schema.as_ref().map(serde_json::to_string).transpose()?,
+
+            _ => None,
+        })
+    }
+
+    fn changes(&self) -> Option<broadcast::Receiver<Change>> {
+        Some(self.changes.subscribe())
+    }
+
+    #[instrument(name = "emails.resolvers.email_template.list", skip_all, fields(namespace = %self.namespace))]
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut paths = Vec::new();
+        for resource in self.store.state() {
+            let Some(ref name) = resource.metadata.name else {
+                continue;
+            };
+
+            let spec = &resource.spec;
+            let variants = spec
+                .locales
+                .iter()
+                .map(|(locale, variant)| (format!("{name}/{locale}"), Some(variant)));
+
+            for (template, variant) in [(name.clone(), None)].into_iter().chain(variants) {
+                let parts = [
+                    ("html", variant.and_then(|v| v.html.as_ref()).or(spec.html.as_ref())),
+                    ("txt", variant.and_then(|v| v.text.as_ref()).or(spec.text.as_ref())),
+                    (
+                        "subject",
+                        variant.and_then(|v| v.subject.as_ref()).or(spec.subject.as_ref()),
+                    ),
+                ];
+
+                paths.extend(
+                    parts
+                        .into_iter()
+                        .filter(|(_, contents)| contents.is_some())
+                        .map(|(part, _)| format!("{template}.{part}")),
+                );
+
+                if spec.context_schema.is_some() {
+                    paths.push(format!("{template}.schema.json"));
+                }
+            }
+        }
+
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EmailTemplate;
+
+    #[test]
+    fn definition_is_up_to_date() {
+        let definition = serde_yaml::to_string(&EmailTemplate::definition()).unwrap();
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/distribution/kubernetes/emailtemplates.yaml"
+        );
+
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap_or_default(),
+            definition,
+            "{path} is out of date, update it with the definition from `EmailTemplate::definition()`"
+        );
+    }
+}
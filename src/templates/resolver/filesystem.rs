@@ -13,35 +13,226 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::path::PathBuf;
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
-use super::TemplateResolver;
+use super::{walk, Change, TemplateResolver};
 use eyre::{Report, Result};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use remi_core::StorageService;
 use remi_fs::{FilesystemStorageConfig, FilesystemStorageService};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
 
 /// Represents a [`TemplateResolver`] that uses the local filesystem as the
 /// resolver's root directory.
+///
+/// The directory is watched for changes (with inotify on Linux), so compiled templates are
+/// evicted from the cache as soon as their files are changed or a ConfigMap volume is remounted.
 #[derive(Debug, Clone)]
-pub struct FilesystemTemplateResolver(FilesystemStorageService);
+pub struct FilesystemTemplateResolver {
+    storage: FilesystemStorageService,
+    directory: PathBuf,
+    changes: broadcast::Sender<Change>,
+    watcher: Arc<Mutex<Option<RecommendedWatcher>>>,
+}
 
 impl FilesystemTemplateResolver {
     pub fn new(config: FilesystemStorageConfig) -> FilesystemTemplateResolver {
-        FilesystemTemplateResolver(FilesystemStorageService::with_config(config))
+        FilesystemTemplateResolver {
+            directory: config.directory(),
+            storage: FilesystemStorageService::with_config(config),
+            changes: broadcast::channel(128).0,
+            watcher: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Starts watching the directory for changes, which are sent to [`changes`][TemplateResolver::changes].
+    fn watch(&self) -> Result<()> {
+        let root = self.directory.canonicalize()?;
+        let sender = self.changes.clone();
+        let mut watcher = notify::recommended_watcher({
+            let root = root.clone();
+            move |event: notify::Result<Event>| {
+                for change in changes(&root, event) {
+                    // there's nothing to invalidate if nothing is subscribed
+                    let _ = sender.send(change);
+                }
+            }
+        })?;
+
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+        *self.watcher.lock().unwrap() = Some(watcher);
+
+        info!(directory = %root.display(), "watching templates directory for changes");
+        Ok(())
+    }
+}
+
+/// Returns the changes that a filesystem event in `root` describes. Hidden files are where
+/// Kubernetes swaps the contents of a ConfigMap volume (`..data`), so anything could have
+/// changed when one of them changes.
+fn changes(root: &Path, event: notify::Result<Event>) -> Vec<Change> {
+    let event = match event {
+        Ok(event) => event,
+        Err(e) => {
+            warn!(error = %e, "unable to watch templates directory");
+            return vec![Change::All];
+        }
+    };
+
+    if matches!(event.kind, EventKind::Access(_)) {
+        return vec![];
+    }
+
+    if event.need_rescan() {
+        return vec![Change::All];
+    }
+
+    let mut changes = Vec::with_capacity(event.paths.len());
+    for path in &event.paths {
+        let Ok(relative) = path.strip_prefix(root) else {
+            continue;
+        };
+
+        let components = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>();
+
+        if components.is_empty() || components.iter().any(|component| component.starts_with('.')) {
+            return vec![Change::All];
+        }
+
+        changes.push(Change::Path(components.join("/")));
+    }
+
+    changes
+}
+
+/// remi-fs only resolves paths that start with `./` from its directory, so
+/// `welcome.html` is treated as `./welcome.html`. remi-fs also opens absolute paths
+/// and follows `..` as-is, so those are rejected to keep paths in the directory.
+fn relative(path: PathBuf) -> Result<PathBuf> {
+    if path.components().any(|component| {
+        matches!(
+            component,
+            Component::RootDir | Component::Prefix(_) | Component::ParentDir
+        )
+    }) {
+        return Err(eyre!("path {} is outside of the templates directory", path.display()));
+    }
+
+    if !path.starts_with("./") {
+        return Ok(Path::new("./").join(path));
     }
+
+    Ok(path)
 }
 
 #[async_trait]
 impl TemplateResolver for FilesystemTemplateResolver {
     async fn init(&self) -> Result<()> {
-        self.0.init().await.map_err(Report::from)
+        self.storage.init().await.map_err(Report::from)?;
+        if let Err(e) = self.watch() {
+            warn!(error = %e, "unable to watch templates directory, templates won't be cached");
+        }
+
+        Ok(())
     }
 
     async fn pull(&self, path: PathBuf) -> Result<Option<String>> {
-        self.0
-            .open(path)
+        let Some(bytes) = self.pull_bytes(path.clone()).await? else {
+            return Ok(None);
+        };
+
+        String::from_utf8(bytes)
+            .map(Some)
+            .map_err(|e| eyre!("file `{}` is not valid utf-8: {e}", path.display()))
+    }
+
+    async fn pull_bytes(&self, path: PathBuf) -> Result<Option<Vec<u8>>> {
+        self.storage
+            .open(relative(path)?)
             .await
-            .map(|bytes| bytes.map(|b| String::from_utf8(b.to_vec()).expect("expected valid utf-8 encoded text")))
+            .map(|bytes| bytes.map(|b| b.to_vec()))
             .map_err(Report::from)
     }
+
+    fn changes(&self) -> Option<broadcast::Receiver<Change>> {
+        self.watcher.lock().unwrap().is_some().then(|| self.changes.subscribe())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        walk(&self.directory).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn changed_files_are_sent() {
+        let directory = std::env::temp_dir().join(format!("emails-fs-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("reset")).unwrap();
+
+        let resolver =
+            FilesystemTemplateResolver::new(FilesystemStorageConfig::new(directory.to_string_lossy().into_owned()));
+
+        resolver.init().await.unwrap();
+        let mut changes = resolver.changes().expect("directory should be watched");
+
+        std::fs::write(directory.join("reset/password.txt"), "Hello, {{name}}!").unwrap();
+        let change = timeout(Duration::from_secs(5), changes.recv()).await.unwrap().unwrap();
+        assert_eq!(change, Change::Path(String::from("reset/password.txt")));
+
+        std::fs::write(directory.join("..data"), "").unwrap();
+        loop {
+            let change = timeout(Duration::from_secs(5), changes.recv()).await.unwrap().unwrap();
+            if change == Change::All {
+                break;
+            }
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn binary_files_are_not_templates() {
+        let directory = std::env::temp_dir().join(format!("emails-fs-binary-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("logo.png"), [0x89, b'P', b'N', b'G', 0xff]).unwrap();
+
+        let resolver =
+            FilesystemTemplateResolver::new(FilesystemStorageConfig::new(directory.to_string_lossy().into_owned()));
+
+        assert!(resolver.pull(PathBuf::from("logo.png")).await.is_err());
+        assert_eq!(
+            resolver.pull_bytes(PathBuf::from("logo.png")).await.unwrap(),
+            Some(vec![0x89, b'P', b'N', b'G', 0xff])
+        );
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn paths_are_confined_to_directory() {
+        assert_eq!(
+            relative(PathBuf::from("welcome.html")).unwrap(),
+            Path::new("./welcome.html")
+        );
+        assert_eq!(
+            relative(PathBuf::from("./reset/password.txt")).unwrap(),
+            Path::new("./reset/password.txt")
+        );
+
+        assert!(relative(PathBuf::from("/etc/passwd")).is_err());
+        assert!(relative(PathBuf::from("../secrets")).is_err());
+        assert!(relative(PathBuf::from("./assets/../../secrets")).is_err());
+    }
 }
@@ -13,25 +13,233 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::TemplateResolver;
-use eyre::{Report, Result};
-use std::path::{Path, PathBuf};
-use tokio::fs;
-use tracing::{info, instrument, warn};
+pub mod signatures;
+pub mod webhook;
+
+use super::{walk, Change, TemplateResolver};
+use crate::{
+    config::{FromEnv, TryFromEnv},
+    var,
+};
+use eyre::{Context, Report, Result};
+use git2::{
+    build::{CheckoutBuilder, RepoBuilder},
+    AutotagOption, Cred, CredentialType, ErrorCode, FetchOptions, Object, ObjectType, Oid, RemoteCallbacks, Repository,
+};
+use serde::{Deserialize, Serialize};
+use signatures::Unverified;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs,
+    sync::{broadcast, watch, Mutex},
+    task::spawn_blocking,
+    time,
+};
+use tracing::{error, info, instrument, warn};
+
+/// Configuration for the [`GitTemplateResolver`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    /// URL of the Git repository that holds the templates, i.e, `https://github.com/charted-dev/email-templates`
+    /// or `git@github.com:charted-dev/email-templates.git` when using SSH.
+    pub repository: String,
+
+    /// Directory to clone the repository into. Default is `./templates`.
+    #[serde(default = "default_directory")]
+    pub directory: PathBuf,
+
+    /// Branch, tag or commit to check out. If this isn't set, then the default branch
+    /// of the repository is used.
+    #[serde(default, alias = "branch", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+
+    /// Username to authenticate with when using a token over HTTPS. Default is `git`, which
+    /// most Git hosts accept when a token is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// Access token to authenticate with over HTTPS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
+    /// SSH keys to authenticate with when the repository is cloned over SSH.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh: Option<SshConfig>,
+
+    /// If set, only commits that were signed by one of the configured keys are checked out. When
+    /// the latest commit can't be verified, the last verified commit keeps being used and the
+    /// `noelware.charted.emails.templates` health check reports that it's not serving.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signatures: Option<signatures::Config>,
+
+    /// Configures a HTTP listener for push webhooks, which refreshes the repository
+    /// as soon as something is pushed to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<webhook::Config>,
+
+    /// Interval, in seconds, to fetch the repository again. Setting this to `0` disables
+    /// fetching, so the repository is only cloned or fetched on startup. Default is `300`
+    /// seconds (5 minutes).
+    #[serde(default = "default_fetch_interval")]
+    pub fetch_interval: u64,
+}
+
+impl TryFromEnv for Config {
+    type Output = Config;
+    type Err = Report;
+
+    fn try_from_env() -> Result<Self::Output, Self::Err> {
+        let repository = var!("EMAILS_TEMPLATES_GIT_REPOSITORY")
+            .map_err(|_| eyre!("missing required `EMAILS_TEMPLATES_GIT_REPOSITORY` environment variable"))?;
+
+        let ssh = var!("EMAILS_TEMPLATES_GIT_SSH_KEYS", is_optional: true).map(|keys| SshConfig {
+            username: var!("EMAILS_TEMPLATES_GIT_SSH_USERNAME", is_optional: true),
+            keys: keys.split(',').map(|key| PathBuf::from(key.trim())).collect(),
+            passphrase: var!("EMAILS_TEMPLATES_GIT_SSH_PASSPHRASE", is_optional: true),
+        });
+
+        Ok(Config {
+            repository,
+            directory: var!("EMAILS_TEMPLATES_GIT_DIRECTORY", to: PathBuf, or_else: default_directory()),
+            reference: var!("EMAILS_TEMPLATES_GIT_REFERENCE", is_optional: true),
+            username: var!("EMAILS_TEMPLATES_GIT_USERNAME", is_optional: true),
+            token: var!("EMAILS_TEMPLATES_GIT_TOKEN", is_optional: true),
+            ssh,
+            signatures: match (
+                var!("EMAILS_TEMPLATES_GIT_SIGNATURES_GPG_KEYS", is_optional: true),
+                var!("EMAILS_TEMPLATES_GIT_SIGNATURES_SSH_KEYS", is_optional: true),
+            ) {
+                (None, None) => None,
+                _ => Some(signatures::Config::from_env()),
+            },
+
+            webhook: match var!("EMAILS_TEMPLATES_GIT_WEBHOOK_SECRET", is_optional: true) {
+                Some(_) => Some(webhook::Config::try_from_env()?),
+                None => None,
+            },
+            fetch_interval: var!("EMAILS_TEMPLATES_GIT_FETCH_INTERVAL", to: u64, or_else: default_fetch_interval()),
+        })
+    }
+}
+
+fn default_directory() -> PathBuf {
+    PathBuf::from("./templates")
+}
+
+#[inline(always)]
+const fn default_fetch_interval() -> u64 {
+    300
+}
+
+/// SSH configuration for the [`GitTemplateResolver`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SshConfig {
+    /// Username to authenticate as. If this isn't set, then the username from the repository
+    /// URL is used, or `git` if it doesn't have one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// Paths to the private keys to try, in order. `~/` is resolved to the home directory.
+    #[serde(default)]
+    pub keys: Vec<PathBuf>,
+
+    /// Passphrase to decrypt the private keys with, if they are encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<String>,
+}
 
 /// Represents a [`TemplateResolver`] that uses Git repositories to locate
 /// template files.
 #[derive(Debug, Clone)]
 pub struct GitTemplateResolver {
+    config: Arc<Config>,
+
     // the root path is the canonical path to use when fetching templates
     root_path: PathBuf,
+
+    // only one clone or fetch can run at a time
+    lock: Arc<Mutex<()>>,
+
+    // whether the latest commit could be verified
+    healthy: Arc<watch::Sender<bool>>,
+
+    // sends a change whenever a different commit was checked out
+    changes: broadcast::Sender<Change>,
 }
 
 impl GitTemplateResolver {
-    pub fn new<P: AsRef<Path>>(root: P) -> GitTemplateResolver {
+    pub fn new(config: Config) -> GitTemplateResolver {
         GitTemplateResolver {
-            root_path: root.as_ref().into(),
+            root_path: config.directory.clone(),
+            config: Arc::new(config),
+            lock: Arc::default(),
+            healthy: Arc::new(watch::channel(true).0),
+            changes: broadcast::channel(16).0,
+        }
+    }
+
+    /// Clones the repository if it wasn't cloned yet, or fetches it otherwise, and checks
+    /// out the configured reference. The working copy is always reset to what the remote
+    /// has, so local changes are discarded.
+    #[instrument(
+        name = "emails.resolvers.git.refresh",
+        skip_all,
+        fields(repository = %self.config.repository)
+    )]
+    pub async fn refresh(&self) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let config = self.config.clone();
+        let (old, new) = match spawn_blocking(move || sync(&config)).await? {
+            Ok(commits) => commits,
+            Err(e) => {
+                if let Some(unverified) = e.downcast_ref::<Unverified>() {
+                    error!(commit = %unverified.commit, "refusing to check out unverified commit, keeping the last verified commit");
+                    self.healthy.send_replace(false);
+                }
+
+                return Err(e);
+            }
+        };
+
+        self.healthy.send_replace(true);
+
+        match old {
+            Some(old) if old == new => {
+                info!(commit = %new, "templates repository is up to date");
+                return Ok(());
+            }
+
+            Some(old) => info!(%old, %new, "updated templates repository"),
+            None => info!(commit = %new, "checked out templates repository"),
+        }
+
+        // there's nothing to invalidate if nothing is subscribed
+        let _ = self.changes.send(Change::All);
+
+        Ok(())
+    }
+
+    /// Locates `path` in the root directory, returning `None` if it doesn't exist.
+    fn locate(&self, path: &Path) -> Result<Option<PathBuf>> {
+        let path = self.root_path.join(path.strip_prefix("./").unwrap_or(path));
+        if !path.try_exists()? {
+            return Ok(None);
+        }
+
+        let canon = path.canonicalize()?;
+        if !canon.starts_with(self.root_path.canonicalize()?) {
+            return Err(eyre!(
+                "path {} is outside of root path [{}]",
+                canon.display(),
+                self.root_path.display()
+            ));
         }
+
+        Ok(Some(canon))
     }
 }
 
@@ -47,9 +255,51 @@ impl TemplateResolver for GitTemplateResolver {
             fs::create_dir_all(self.root_path.clone()).await?;
         }
 
+        // a checkout from before a restart keeps being served if the repository can't be refreshed
+        // (i.e, the network is down or the latest commit can't be verified)
+        if let Err(e) = self.refresh().await {
+            if !checked_out(&self.root_path) {
+                return Err(e);
+            }
+
+            error!(repository = self.config.repository, error = %e, "unable to refresh templates repository, serving the existing checkout");
+            sentry::capture_error(&*e);
+        }
+
+        if let Some(ref config) = self.config.webhook {
+            webhook::serve(self.clone(), config)?;
+        }
+
+        if self.config.fetch_interval == 0 {
+            return Ok(());
+        }
+
+        let resolver = self.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(resolver.config.fetch_interval));
+
+            // the first tick completes immediately, and we just fetched
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = resolver.refresh().await {
+                    error!(repository = resolver.config.repository, error = %e, "unable to fetch templates repository");
+                    sentry::capture_error(&*e);
+                }
+            }
+        });
+
         Ok(())
     }
 
+    fn healthy(&self) -> Option<watch::Receiver<bool>> {
+        Some(self.healthy.subscribe())
+    }
+
+    fn changes(&self) -> Option<broadcast::Receiver<Change>> {
+        Some(self.changes.subscribe())
+    }
+
     #[instrument(
         name = "emails.resolvers.git.pull",
         skip_all,
@@ -59,19 +309,284 @@ impl TemplateResolver for GitTemplateResolver {
     async fn pull(&self, path: PathBuf) -> Result<Option<String>> {
         info!("pulling path from root directory");
 
-        let canon = path.canonicalize()?;
-        if !canon.starts_with(self.root_path.clone()) {
-            return Err(eyre!(
-                "path {} is outside of root path [{}]",
-                canon.display(),
-                self.root_path.display()
-            ));
-        }
+        let Some(canon) = self.locate(&path)? else {
+            return Ok(None);
+        };
+
+        fs::read_to_string(canon).await.map(Some).map_err(Report::from)
+    }
+
+    #[instrument(
+        name = "emails.resolvers.git.pull_bytes",
+        skip_all,
+        root = tracing::field::display(self.root_path.display()),
+        path = tracing::field::display(path.display()),
+    )]
+    async fn pull_bytes(&self, path: PathBuf) -> Result<Option<Vec<u8>>> {
+        info!("pulling path from root directory");
 
-        if !canon.try_exists()? {
+        let Some(canon) = self.locate(&path)? else {
             return Ok(None);
+        };
+
+        fs::read(canon).await.map(Some).map_err(Report::from)
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        walk(&self.root_path).await
+    }
+}
+
+/// Clones or fetches the repository and checks out the configured reference, returning
+/// the commit that was checked out before (if the repository was already cloned) and the
+/// commit that is checked out now.
+fn sync(config: &Config) -> Result<(Option<Oid>, Oid)> {
+    let (repo, old) = match Repository::open(&config.directory) {
+        Ok(repo) => {
+            let old = repo.head().ok().and_then(|head| head.target());
+            repo.remote_set_url("origin", &config.repository)?;
+            repo.find_remote("origin")?.fetch(
+                &["+refs/heads/*:refs/remotes/origin/*"],
+                Some(&mut fetch_options(config)),
+                None,
+            )?;
+
+            (repo, old)
         }
 
-        fs::read_to_string(canon).await.map(Some).map_err(Report::from)
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            info!(
+                repository = config.repository,
+                directory = %config.directory.display(),
+                "cloning templates repository"
+            );
+
+            // files are checked out below, once the commit was verified
+            let mut checkout = CheckoutBuilder::new();
+            checkout.dry_run();
+
+            let repo = RepoBuilder::new()
+                .fetch_options(fetch_options(config))
+                .with_checkout(checkout)
+                .clone(&config.repository, &config.directory)
+                .with_context(|| format!("unable to clone repository [{}]", config.repository))?;
+
+            (repo, None)
+        }
+
+        Err(e) => return Err(e.into()),
+    };
+
+    let (commit, branch) = resolve(&repo, config)?;
+    if let Some(ref signatures) = config.signatures {
+        signatures::verify(&repo, commit.id(), signatures)?;
+    }
+
+    repo.checkout_tree(&commit, Some(CheckoutBuilder::new().force()))?;
+    match branch {
+        Some(branch) => {
+            let name = format!("refs/heads/{branch}");
+            repo.reference(&name, commit.id(), true, "fetched from origin")?;
+            repo.set_head(&name)?;
+        }
+
+        None => repo.set_head_detached(commit.id())?,
+    }
+
+    Ok((old, commit.id()))
+}
+
+/// Returns whether a commit was checked out in `directory` already. Clones of commits that
+/// couldn't be verified don't have their files checked out, so their index is empty.
+fn checked_out(directory: &Path) -> bool {
+    Repository::open(directory)
+        .and_then(|repo| repo.index())
+        .map_or(false, |index| !index.is_empty())
+}
+
+/// Resolves the configured reference to a commit. Branches are looked up first, then
+/// tags and then commits. If the reference is a branch, its name is returned as well.
+fn resolve<'r>(repo: &'r Repository, config: &Config) -> Result<(Object<'r>, Option<String>)> {
+    let reference = match config.reference {
+        Some(ref reference) => reference.clone(),
+        None => default_branch(repo)?,
+    };
+
+    if let Ok(branch) = repo.find_reference(&format!("refs/remotes/origin/{reference}")) {
+        return Ok((branch.peel(ObjectType::Commit)?, Some(reference)));
+    }
+
+    if let Ok(tag) = repo.find_reference(&format!("refs/tags/{reference}")) {
+        return Ok((tag.peel(ObjectType::Commit)?, None));
+    }
+
+    let commit = repo
+        .revparse_single(&reference)
+        .and_then(|object| object.peel(ObjectType::Commit))
+        .with_context(|| {
+            format!(
+                "unable to find branch, tag or commit [{reference}] in repository [{}]",
+                config.repository
+            )
+        })?;
+
+    Ok((commit, None))
+}
+
+/// Returns the default branch of the `origin` remote, which is recorded when the
+/// repository is cloned.
+fn default_branch(repo: &Repository) -> Result<String> {
+    let head = repo.find_reference("refs/remotes/origin/HEAD")?;
+    let Some(target) = head.symbolic_target() else {
+        return Err(eyre!("expected `refs/remotes/origin/HEAD` to point to a branch"));
+    };
+
+    Ok(target.trim_start_matches("refs/remotes/origin/").to_owned())
+}
+
+fn fetch_options(config: &Config) -> FetchOptions<'_> {
+    let mut keys = config.ssh.iter().flat_map(|ssh| ssh.keys.iter());
+    let mut tried_token = false;
+
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |_, username_from_url, allowed| {
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(ssh_username(config, username_from_url));
+        }
+
+        if allowed.contains(CredentialType::SSH_KEY) && config.ssh.is_some() {
+            // libgit2 calls this again if a key was rejected, so every key is tried once
+            let Some(key) = keys.next() else {
+                return Err(git2::Error::from_str("none of the configured SSH keys were accepted"));
+            };
+
+            let passphrase = config.ssh.as_ref().and_then(|ssh| ssh.passphrase.as_deref());
+            return Cred::ssh_key(
+                ssh_username(config, username_from_url),
+                None,
+                &expand_home(key),
+                passphrase,
+            );
+        }
+
+        if let (true, Some(token)) = (allowed.contains(CredentialType::USER_PASS_PLAINTEXT), &config.token) {
+            if tried_token {
+                return Err(git2::Error::from_str("the configured token was rejected"));
+            }
+
+            tried_token = true;
+            return Cred::userpass_plaintext(config.username.as_deref().unwrap_or("git"), token);
+        }
+
+        Cred::default()
+    });
+
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks).download_tags(AutotagOption::All);
+
+    options
+}
+
+fn ssh_username<'a>(config: &'a Config, username_from_url: Option<&'a str>) -> &'a str {
+    config
+        .ssh
+        .as_ref()
+        .and_then(|ssh| ssh.username.as_deref())
+        .or(username_from_url)
+        .unwrap_or("git")
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Ok(path), Some(home)) => Path::new(&home).join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checked_out, signatures, sync, Config, Unverified};
+    use git2::{Repository, Signature};
+    use std::{fs, path::Path};
+    use uuid::Uuid;
+
+    fn commit(repo: &Repository, file: &str, contents: &str) {
+        fs::write(repo.workdir().unwrap().join(file), contents).unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(file)).unwrap();
+        index.write().unwrap();
+
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("Noel", "noel@example.com").unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "update templates",
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn clone_and_fetch_default_branch() {
+        let root = std::env::temp_dir().join(format!("charted-emails-{}", Uuid::new_v4()));
+        let origin = Repository::init(root.join("origin")).unwrap();
+        commit(&origin, "welcome.html", "hello");
+
+        let config = Config {
+            repository: root.join("origin").display().to_string(),
+            directory: root.join("clone"),
+            reference: None,
+            username: None,
+            token: None,
+            ssh: None,
+            signatures: None,
+            webhook: None,
+            fetch_interval: 0,
+        };
+
+        let (old, first) = sync(&config).unwrap();
+        assert_eq!(old, None);
+        assert!(checked_out(&config.directory));
+        assert_eq!(fs::read_to_string(root.join("clone/welcome.html")).unwrap(), "hello");
+
+        commit(&origin, "welcome.html", "hello, world");
+        assert_eq!(sync(&config).unwrap().0, Some(first));
+        assert_eq!(
+            fs::read_to_string(root.join("clone/welcome.html")).unwrap(),
+            "hello, world"
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn reject_unsigned_commits() {
+        let root = std::env::temp_dir().join(format!("charted-emails-{}", Uuid::new_v4()));
+        let origin = Repository::init(root.join("origin")).unwrap();
+        commit(&origin, "welcome.html", "hello");
+
+        let config = Config {
+            repository: root.join("origin").display().to_string(),
+            directory: root.join("clone"),
+            reference: None,
+            username: None,
+            token: None,
+            ssh: None,
+            signatures: Some(signatures::Config::default()),
+            webhook: None,
+            fetch_interval: 0,
+        };
+
+        let error = sync(&config).unwrap_err();
+        assert!(error.downcast_ref::<Unverified>().is_some());
+        assert!(!root.join("clone/welcome.html").exists());
+        assert!(!checked_out(&config.directory));
+
+        fs::remove_dir_all(root).unwrap();
     }
 }
@@ -0,0 +1,187 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verifies that commits in the templates repository were signed by a trusted key before
+//! they are checked out. Signatures are verified with the `gpg` and `ssh-keygen` binaries,
+//! which need to be installed when this is enabled.
+
+use crate::{config::FromEnv, var};
+use eyre::{Context, Result};
+use git2::{ErrorCode, Oid, Repository};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display, Formatter},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+use uuid::Uuid;
+
+/// Configures the keys that commits need to be signed with. A commit is trusted if it
+/// was signed by any of them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    /// Paths to armored GPG public keys.
+    #[serde(default)]
+    pub gpg_keys: Vec<PathBuf>,
+
+    /// Paths to SSH public keys, in the `ssh-ed25519 AAAA...` format. Files can contain
+    /// more than one key, one per line.
+    #[serde(default)]
+    pub ssh_keys: Vec<PathBuf>,
+}
+
+impl FromEnv for Config {
+    type Output = Config;
+
+    fn from_env() -> Self::Output {
+        let paths = |keys: String| keys.split(',').map(|key| PathBuf::from(key.trim())).collect();
+
+        Config {
+            gpg_keys: var!("EMAILS_TEMPLATES_GIT_SIGNATURES_GPG_KEYS", mapper: paths).unwrap_or_default(),
+            ssh_keys: var!("EMAILS_TEMPLATES_GIT_SIGNATURES_SSH_KEYS", mapper: paths).unwrap_or_default(),
+        }
+    }
+}
+
+/// Error that is returned when a commit couldn't be verified.
+#[derive(Debug)]
+pub struct Unverified {
+    pub commit: Oid,
+    pub reason: String,
+}
+
+impl Display for Unverified {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "commit {} couldn't be verified: {}", self.commit, self.reason)
+    }
+}
+
+impl std::error::Error for Unverified {}
+
+/// Verifies that `commit` was signed by one of the configured keys. If it wasn't, then
+/// an [`Unverified`] error is returned.
+pub(super) fn verify(repo: &Repository, commit: Oid, config: &Config) -> Result<()> {
+    let unverified = |reason: &str| -> Result<()> {
+        Err(Unverified {
+            commit,
+            reason: reason.to_owned(),
+        }
+        .into())
+    };
+
+    let (signature, data) = match repo.extract_signature(&commit, None) {
+        Ok(extracted) => extracted,
+        Err(e) if e.code() == ErrorCode::NotFound => return unverified("commit isn't signed"),
+        Err(e) => return Err(e.into()),
+    };
+
+    let is_ssh = signature.starts_with(b"-----BEGIN SSH SIGNATURE-----");
+    if is_ssh && config.ssh_keys.is_empty() {
+        return unverified("commit was signed with SSH, but no SSH keys are configured");
+    }
+
+    if !is_ssh && config.gpg_keys.is_empty() {
+        return unverified("commit was signed with GPG, but no GPG keys are configured");
+    }
+
+    let directory = std::env::temp_dir().join(format!("charted-emails-{}", Uuid::new_v4()));
+    fs::create_dir_all(&directory)?;
+
+    let verified = match is_ssh {
+        true => verify_ssh(&directory, &signature, &data, &config.ssh_keys),
+        false => verify_gpg(&directory, &signature, &data, &config.gpg_keys),
+    };
+
+    fs::remove_dir_all(&directory)?;
+    match verified? {
+        true => Ok(()),
+        false => unverified("signature doesn't belong to any of the configured keys"),
+    }
+}
+
+fn verify_ssh(directory: &Path, signature: &[u8], data: &[u8], keys: &[PathBuf]) -> Result<bool> {
+    // every key is allowed to sign as any identity, since we only care about the key itself
+    let mut allowed_signers = String::new();
+    for path in keys {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("unable to read SSH key [{}]", path.display()))?;
+
+        for key in contents.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            allowed_signers.push_str(&format!("* {key}\n"));
+        }
+    }
+
+    fs::write(directory.join("allowed_signers"), allowed_signers)?;
+    fs::write(directory.join("commit.sig"), signature)?;
+
+    let output = run(
+        Command::new("ssh-keygen")
+            .args(["-Y", "verify", "-n", "git", "-I", "templates", "-f"])
+            .arg(directory.join("allowed_signers"))
+            .arg("-s")
+            .arg(directory.join("commit.sig")),
+        data,
+    )?;
+
+    Ok(output.status.success())
+}
+
+fn verify_gpg(directory: &Path, signature: &[u8], data: &[u8], keys: &[PathBuf]) -> Result<bool> {
+    let home = directory.join("gnupg");
+    fs::create_dir_all(&home)?;
+
+    let import = run(
+        Command::new("gpg")
+            .env("GNUPGHOME", &home)
+            .args(["--batch", "--import"])
+            .args(keys),
+        &[],
+    )?;
+
+    if !import.status.success() {
+        return Err(eyre!(
+            "unable to import GPG keys: {}",
+            String::from_utf8_lossy(&import.stderr).trim()
+        ));
+    }
+
+    fs::write(directory.join("commit.asc"), signature)?;
+
+    let output = run(
+        Command::new("gpg")
+            .env("GNUPGHOME", &home)
+            .args(["--batch", "--status-fd", "1", "--verify"])
+            .arg(directory.join("commit.asc"))
+            .arg("-"),
+        data,
+    )?;
+
+    Ok(output.status.success() && String::from_utf8_lossy(&output.stdout).contains("[GNUPG:] VALIDSIG "))
+}
+
+/// Runs `command` with `stdin` written to its standard input.
+fn run(command: &mut Command, stdin: &[u8]) -> Result<Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("unable to run {:?}", command.get_program()))?;
+
+    child.stdin.take().expect("stdin is piped").write_all(stdin)?;
+    child.wait_with_output().map_err(Into::into)
+}
@@ -0,0 +1,199 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP listener for push webhooks from GitHub, GitLab and Gitea, which refreshes the
+//! [`GitTemplateResolver`] as soon as something was pushed to the templates repository.
+//!
+//! * GitHub and Gitea sign the request body with HMAC-SHA256 and send the signature
+//!   in the `X-Hub-Signature-256` and `X-Gitea-Signature` headers.
+//! * GitLab sends the secret as-is in the `X-Gitlab-Token` header.
+
+use super::GitTemplateResolver;
+use crate::{config::TryFromEnv, var};
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Router, Server,
+};
+use eyre::{Report, Result};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{net::SocketAddr, sync::Arc};
+use tracing::{error, info, warn};
+
+/// Configuration for the push webhook listener.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    /// Secret that was configured on the webhook, which is used to verify that requests
+    /// were sent by the Git host.
+    pub secret: String,
+
+    /// Host to bind the listener to. Default is `0.0.0.0`.
+    #[serde(default = "host")]
+    pub host: String,
+
+    /// Port to bind the listener to. Default is `32122`.
+    #[serde(default = "port")]
+    pub port: u16,
+}
+
+impl TryFromEnv for Config {
+    type Output = Config;
+    type Err = Report;
+
+    fn try_from_env() -> Result<Self::Output, Self::Err> {
+        Ok(Config {
+            secret: var!("EMAILS_TEMPLATES_GIT_WEBHOOK_SECRET")
+                .map_err(|_| eyre!("missing required `EMAILS_TEMPLATES_GIT_WEBHOOK_SECRET` environment variable"))?,
+
+            host: var!("EMAILS_TEMPLATES_GIT_WEBHOOK_HOST", or_else: host()),
+            port: var!("EMAILS_TEMPLATES_GIT_WEBHOOK_PORT", to: u16, or_else: port()),
+        })
+    }
+}
+
+impl Config {
+    pub fn addr(&self) -> Result<SocketAddr> {
+        format!("{}:{}", self.host, self.port)
+            .parse()
+            .map_err(|_| eyre!("unable to construct SocketAddr from configured webhook host and port"))
+    }
+}
+
+#[inline(always)]
+fn host() -> String {
+    String::from("0.0.0.0")
+}
+
+#[inline(always)]
+const fn port() -> u16 {
+    32122
+}
+
+#[derive(Clone)]
+struct WebhookState {
+    resolver: GitTemplateResolver,
+    secret: Arc<str>,
+}
+
+/// Binds the webhook listener and serves it in the background. Push events are accepted
+/// on `POST /webhook`.
+pub(super) fn serve(resolver: GitTemplateResolver, config: &Config) -> Result<()> {
+    let addr = config.addr()?;
+    let router = Router::new().route("/webhook", post(push)).with_state(WebhookState {
+        resolver,
+        secret: Arc::from(config.secret.as_str()),
+    });
+
+    let server = Server::try_bind(&addr)?.serve(router.into_make_service());
+    info!(%addr, "listening for push webhooks");
+
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!(error = %e, "webhook listener stopped");
+            sentry::capture_error(&e);
+        }
+    });
+
+    Ok(())
+}
+
+async fn push(State(state): State<WebhookState>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    if !verify(&headers, &body, &state.secret) {
+        warn!("received webhook with an invalid or missing signature");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let event = ["x-github-event", "x-gitea-event", "x-gitlab-event"]
+        .into_iter()
+        .find_map(|name| headers.get(name))
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    if !matches!(event, "push" | "Push Hook") {
+        info!(event, "ignoring webhook event that isn't a push");
+        return StatusCode::NO_CONTENT;
+    }
+
+    // Git hosts time out webhooks quickly, so the fetch happens in the background
+    tokio::spawn(async move {
+        if let Err(e) = state.resolver.refresh().await {
+            error!(error = %e, "unable to refresh templates repository from webhook");
+            sentry::capture_error(&*e);
+        }
+    });
+
+    StatusCode::ACCEPTED
+}
+
+/// Verifies that a webhook request was sent with the configured `secret`.
+fn verify(headers: &HeaderMap, body: &[u8], secret: &str) -> bool {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(signature) = header("x-hub-signature-256") {
+        return signature
+            .strip_prefix("sha256=")
+            .map_or(false, |signature| verify_hmac(body, secret, signature));
+    }
+
+    if let Some(signature) = header("x-gitea-signature") {
+        return verify_hmac(body, secret, signature);
+    }
+
+    if let Some(token) = header("x-gitlab-token") {
+        // compares in constant time, so the secret can't be guessed from response times
+        return token.len() == secret.len()
+            && token.bytes().zip(secret.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0;
+    }
+
+    false
+}
+
+fn verify_hmac(body: &[u8], secret: &str, signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify;
+    use axum::http::{HeaderMap, HeaderValue};
+
+    #[test]
+    fn verify_github_signature() {
+        // from https://docs.github.com/en/webhooks/using-webhooks/validating-webhook-deliveries
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-hub-signature-256",
+            HeaderValue::from_static("sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"),
+        );
+
+        assert!(verify(&headers, b"Hello, World!", "It's a Secret to Everybody"));
+        assert!(!verify(&headers, b"Hello, World!", "not the secret"));
+        assert!(!verify(
+            &HeaderMap::new(),
+            b"Hello, World!",
+            "It's a Secret to Everybody"
+        ));
+    }
+}
@@ -13,113 +13,311 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::TemplateResolver;
-use eyre::{Report, Result};
+use super::{Change, TemplateResolver};
+use crate::{config::FromEnv, var};
+use eyre::Result;
+use futures::StreamExt;
 use k8s_openapi::api::core::v1::ConfigMap;
-use kube::{Api, Client};
+use kube::{
+    runtime::{
+        reflector::{self, ObjectRef, Store},
+        watcher, WatchStreamExt,
+    },
+    Api, Client, Resource,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    borrow::Cow,
     fmt::{self, Debug, Formatter},
+    hash::Hash,
     path::PathBuf,
+    sync::Arc,
 };
-use tracing::{info, instrument};
+use tokio::sync::broadcast;
+use tracing::{info, instrument, warn};
+
+/// Configuration for the [`KubernetesTemplateResolver`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    /// Namespace to find ConfigMaps in. If this isn't set, then the namespace of the
+    /// Kubernetes configuration is used, which is the namespace the service runs in when
+    /// it's running inside a Pod.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+
+    /// [Label selector](https://kubernetes.io/docs/concepts/overview/working-with-objects/labels/#label-selectors)
+    /// that ConfigMaps need to match to be used, i.e, `app.kubernetes.io/component=email-templates`. If this isn't
+    /// set, then every ConfigMap in the namespace can be used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label_selector: Option<String>,
+
+    /// Whether to also read keys in the `binaryData` of ConfigMaps, which is useful for
+    /// images that are embedded in templates. Default is `false`.
+    #[serde(default)]
+    pub binary_data: bool,
+}
+
+impl FromEnv for Config {
+    type Output = Config;
+
+    fn from_env() -> Self::Output {
+        Config {
+            namespace: var!("EMAILS_TEMPLATES_KUBERNETES_NAMESPACE", is_optional: true),
+            label_selector: var!("EMAILS_TEMPLATES_KUBERNETES_LABEL_SELECTOR", is_optional: true),
+            binary_data: var!("EMAILS_TEMPLATES_KUBERNETES_BINARY_DATA", to: bool, or_else: false),
+        }
+    }
+}
 
 /// Represents a [`TemplateResolver`] implementation that uses a Kubernetes
 /// [`ConfigMap`](https://kubernetes.io/docs/concepts/configuration/configmap) to resolve templates in a given namespace.
 ///
-/// The resolver doesn't need to create or destroy ConfigMaps, all it needs is read permissions, which you can
-/// easily configure with the [official Helm chart](https://charts.noelware.org/~/charted/emails).
+/// Templates are referenced as `{configmap}/{key}`, so `welcome/index.html` is the `index.html` key
+/// in the `welcome` ConfigMap.
+///
+/// ConfigMaps are watched and kept in an in-memory cache, so pulling a template doesn't need to contact the API
+/// server and keeps working while the API server is unavailable.
+///
+/// The resolver doesn't need to create or destroy ConfigMaps, all it needs is read permissions (`list` and `watch`),
+/// which you can easily configure with the [official Helm chart](https://charts.noelware.org/~/charted/emails).
 ///
 /// > **WARNING**: The resolver supports using Kubernetes v1.26+ as of 15/10/23.
 #[derive(Clone)]
 pub struct KubernetesTemplateResolver {
-    client: Client,
-    namespace: Cow<'static, str>,
+    store: Store<ConfigMap>,
+    namespace: String,
+    changes: broadcast::Sender<Change>,
+    config: Config,
 }
 
 impl Debug for KubernetesTemplateResolver {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("KubernetesTemplateResolver")
             .field("namespace", &self.namespace)
-            .field("client", &"<kube::Client>")
+            .field("config", &self.config)
+            .field("cached", &self.store.len())
             .finish()
     }
 }
 
 impl KubernetesTemplateResolver {
     /// Creates a new [`KubernetesTemplateResolver`] instance.
-    pub async fn new(namespace: Cow<'static, str>) -> Result<KubernetesTemplateResolver> {
-        info!(%namespace, "contacting Kubernetes API");
+    pub async fn new(config: Config) -> Result<KubernetesTemplateResolver> {
+        let client = Client::try_default().await?;
+        let namespace = config
+            .namespace
+            .clone()
+            .unwrap_or_else(|| client.default_namespace().to_owned());
 
+        info!(%namespace, "watching ConfigMaps in namespace");
+
+        let changes = broadcast::channel(16).0;
         Ok(KubernetesTemplateResolver {
+            store: reflect(
+                Api::namespaced(client, &namespace),
+                config.label_selector.as_deref(),
+                changes.clone(),
+            ),
             namespace,
-            client: Client::try_default().await?,
+            changes,
+            config,
         })
     }
+
+    /// Finds the ConfigMap that `path` points to, returning it with the key in it. Paths that
+    /// can't point to a ConfigMap key (i.e, `welcome` or `weow/fluff/heck.tmpl`) don't exist, so
+    /// other resolvers in a chain can still resolve them.
+    fn find(&self, path: PathBuf) -> Option<(Arc<ConfigMap>, String)> {
+        let path = path.strip_prefix("./").unwrap_or(&path);
+        let (name, key) = path.to_str()?.split_once('/')?;
+
+        // keys of a ConfigMap can only consist of alphanumeric characters, `-`, `_` or `.`
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|ch| ch.is_alphanumeric() || matches!(ch, '-' | '_' | '.'))
+        {
+            return None;
+        }
+
+        // ConfigMaps that don't match the label selector aren't watched, so they are
+        // treated as if they don't exist
+        let cm = self.store.get(&ObjectRef::new(name).within(&self.namespace));
+        cm.map(|cm| (cm, key.to_owned()))
+    }
+}
+
+/// Watches all objects in `api` that match `label_selector` and keeps them in a [`Store`] in
+/// the background. If the watch fails, then it is retried with a backoff and the store keeps
+/// the objects that it had. A [`Change`] is sent whenever an object is applied or deleted.
+pub(super) fn reflect<K>(api: Api<K>, label_selector: Option<&str>, changes: broadcast::Sender<Change>) -> Store<K>
+where
+    K: Resource + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+    K::DynamicType: Default + Eq + Hash + Clone,
+{
+    let config = match label_selector {
+        Some(selector) => watcher::Config::default().labels(selector),
+        None => watcher::Config::default(),
+    };
+
+    let (store, writer) = reflector::store();
+    let stream = reflector::reflector(writer, watcher(api, config))
+        .default_backoff()
+        .touched_objects();
+
+    tokio::spawn(stream.for_each(move |event| {
+        match event {
+            // there's nothing to invalidate if nothing is subscribed
+            Ok(_) => {
+                let _ = changes.send(Change::All);
+            }
+
+            Err(e) => warn!(
+                kind = %K::kind(&K::DynamicType::default()),
+                error = %e,
+                "unable to watch resources, retrying; templates are served from the cache until then"
+            ),
+        }
+
+        futures::future::ready(())
+    }));
+
+    store
 }
 
 #[async_trait]
 impl TemplateResolver for KubernetesTemplateResolver {
+    async fn init(&self) -> Result<()> {
+        self.store.wait_until_ready().await?;
+        info!(configmaps = self.store.len(), "cached ConfigMaps");
+
+        Ok(())
+    }
+
     #[instrument(
         name = "emails.resolvers.kubernetes.pull",
         skip_all,
-        path = tracing::field::display(path.display()),
+        fields(path = %path.display())
     )]
     async fn pull(&self, path: PathBuf) -> Result<Option<String>> {
-        let path = path.strip_prefix("./").unwrap_or(&path);
-        let Some(s) = path.to_str() else {
-            return Err(eyre!("received invalid utf-8 path"));
+        let Some((cm, key)) = self.find(path) else {
+            return Ok(None);
         };
 
-        let (name, filename) = match s.split_once('/') {
-            Some((_, filename)) if filename.contains('/') => {
-                return Err(eyre!("expected one slash (i.e, 'weow/fluff.tmpl')"))
-            }
+        Ok(cm.data.as_ref().and_then(|data| data.get(&key)).cloned())
+    }
 
-            Some(tuple) => tuple,
-            None => return Err(eyre!("empty string or didn't find a '/' delimiter in path")),
+    #[instrument(
+        name = "emails.resolvers.kubernetes.pull_bytes",
+        skip_all,
+        fields(path = %path.display())
+    )]
+    async fn pull_bytes(&self, path: PathBuf) -> Result<Option<Vec<u8>>> {
+        let Some((cm, key)) = self.find(path) else {
+            return Ok(None);
         };
 
-        if s.contains('/') {
-            return Err(eyre!("path cannot contain more slashes"));
+        if let Some(value) = cm.data.as_ref().and_then(|data| data.get(&key)) {
+            return Ok(Some(value.clone().into_bytes()));
         }
 
-        // find a config map
-        let api = Api::<ConfigMap>::namespaced(self.client.clone(), &self.namespace);
-        match api.get(name).await {
-            Ok(cm) => {
-                // first, we need to check if the filename that we were
-                // given is a valid key in a ConfigMap
-                for (at, ch) in filename.chars().enumerate() {
-                    if ch.is_alphanumeric() {
-                        continue;
-                    }
-
-                    if ch == '_' {
-                        continue;
-                    }
-
-                    if ch == '-' {
-                        continue;
-                    }
-
-                    if ch == '.' {
-                        continue;
-                    }
-
-                    return Err(eyre!(
-                        "filename given [{filename}@{at} (char '{ch}')] was not a valid ConfigMap key path"
-                    ));
-                }
-
-                let Some(data) = cm.data else {
-                    return Err(eyre!("expected `data` key in ConfigMap {name}"));
-                };
-
-                Ok(data.get(filename).cloned())
-            }
+        if !self.config.binary_data {
+            return Ok(None);
+        }
+
+        Ok(cm
+            .binary_data
+            .as_ref()
+            .and_then(|data| data.get(&key))
+            .map(|bytes| bytes.0.clone()))
+    }
+
+    fn changes(&self) -> Option<broadcast::Receiver<Change>> {
+        Some(self.changes.subscribe())
+    }
+
+    #[instrument(name = "emails.resolvers.kubernetes.list", skip_all, fields(namespace = %self.namespace))]
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut paths = Vec::new();
+        for cm in self.store.state() {
+            let Some(ref name) = cm.metadata.name else {
+                continue;
+            };
+
+            let keys = cm.data.iter().flat_map(|data| data.keys());
+            let binary_keys = cm
+                .binary_data
+                .iter()
+                .filter(|_| self.config.binary_data)
+                .flat_map(|data| data.keys());
 
-            Err(e) => return Err(Report::from(e)),
+            paths.extend(keys.chain(binary_keys).map(|key| format!("{name}/{key}")));
         }
+
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::templates::{
+        compiled::CompiledTemplate,
+        resolver::{composite::CompositeTemplateResolver, filesystem::FilesystemTemplateResolver},
+    };
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use remi_fs::FilesystemStorageConfig;
+    use std::collections::BTreeMap;
+
+    fn resolver(configmaps: Vec<ConfigMap>) -> KubernetesTemplateResolver {
+        let (store, mut writer) = reflector::store();
+        for cm in configmaps {
+            writer.apply_watcher_event(&watcher::Event::Applied(cm));
+        }
+
+        KubernetesTemplateResolver {
+            store,
+            namespace: String::from("charted"),
+            changes: broadcast::channel(1).0,
+            config: Config::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn chain_falls_through_paths_that_are_not_configmap_keys() {
+        let directory = std::env::temp_dir().join(format!("emails-chain-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("welcome.html"), "<p>Hello, {{name}}!</p>").unwrap();
+        std::fs::write(directory.join("logo.png"), "png").unwrap();
+
+        let kubernetes = resolver(vec![ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(String::from("reset")),
+                namespace: Some(String::from("charted")),
+                ..Default::default()
+            },
+            data: Some(BTreeMap::from([(
+                String::from("index.html"),
+                String::from("<p>Reset</p>"),
+            )])),
+            ..Default::default()
+        }]);
+
+        let chain = CompositeTemplateResolver::new(vec![
+            Box::new(kubernetes),
+            Box::new(FilesystemTemplateResolver::new(FilesystemStorageConfig::new(
+                directory.to_string_lossy().into_owned(),
+            ))),
+        ]);
+
+        assert!(CompiledTemplate::pull(&chain, "welcome").await.unwrap().is_some());
+        assert!(CompiledTemplate::pull(&chain, "reset/index").await.unwrap().is_some());
+        assert_eq!(
+            chain.pull_bytes("logo.png".into()).await.unwrap().as_deref(),
+            Some(&b"png"[..])
+        );
+        assert_eq!(chain.pull("reset/index/de.html".into()).await.unwrap(), None);
+
+        std::fs::remove_dir_all(directory).unwrap();
     }
 }
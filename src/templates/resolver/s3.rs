@@ -0,0 +1,211 @@
+// 🐻‍❄️💌 email-service: charted's email service built in Rust that can be connected via gRPC
+// Copyright 2023 Noelware, LLC. <team@noelware.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::TemplateResolver;
+use crate::{config::TryFromEnv, var};
+use aws_sdk_s3::{
+    config::{AppName, BehaviorVersion, Credentials, Region},
+    Client,
+};
+use eyre::{Context, Report, Result};
+use remi_s3::S3StorageConfig;
+use std::path::{Path, PathBuf};
+use tracing::{info, instrument};
+
+impl TryFromEnv for S3StorageConfig {
+    type Output = S3StorageConfig;
+    type Err = Report;
+
+    fn try_from_env() -> Result<Self::Output, Self::Err> {
+        let mut config = S3StorageConfig::default();
+        config.bucket = var!("EMAILS_TEMPLATES_S3_BUCKET")
+            .map_err(|_| eyre!("missing required `EMAILS_TEMPLATES_S3_BUCKET` environment variable"))?;
+
+        config.access_key_id = var!("EMAILS_TEMPLATES_S3_ACCESS_KEY_ID")
+            .map_err(|_| eyre!("missing required `EMAILS_TEMPLATES_S3_ACCESS_KEY_ID` environment variable"))?;
+
+        config.secret_access_key = var!("EMAILS_TEMPLATES_S3_SECRET_ACCESS_KEY")
+            .map_err(|_| eyre!("missing required `EMAILS_TEMPLATES_S3_SECRET_ACCESS_KEY` environment variable"))?;
+
+        config.prefix = var!("EMAILS_TEMPLATES_S3_PREFIX", is_optional: true);
+        config.endpoint = var!("EMAILS_TEMPLATES_S3_ENDPOINT", is_optional: true);
+        config.region = var!("EMAILS_TEMPLATES_S3_REGION", is_optional: true).map(Region::new);
+        config.enforce_path_access_style =
+            var!("EMAILS_TEMPLATES_S3_ENFORCE_PATH_ACCESS_STYLE", to: bool, or_else: false);
+
+        Ok(config)
+    }
+}
+
+/// Represents a [`TemplateResolver`] that resolves templates from an S3-compatible bucket, like
+/// AWS S3 or MinIO. Templates are objects in `${templates.s3.bucket}`, optionally under
+/// `${templates.s3.prefix}`, so `welcome.html` is the `{prefix}/welcome.html` object.
+///
+/// The resolver only needs read permissions (`s3:GetObject` and `s3:ListBucket`) and never
+/// creates the bucket.
+#[derive(Debug, Clone)]
+pub struct S3TemplateResolver {
+    client: Client,
+    config: S3StorageConfig,
+}
+
+impl S3TemplateResolver {
+    /// Creates a new [`S3TemplateResolver`] instance.
+    pub fn new(config: S3StorageConfig) -> S3TemplateResolver {
+        // remi-s3 0.4's `S3StorageService` reads objects into an empty buffer (so every object
+        // is empty), so only its configuration is used and the client is built here instead.
+        let mut builder = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .credentials_provider(Credentials::new(
+                config.access_key_id.clone(),
+                config.secret_access_key.clone(),
+                None,
+                None,
+                "email-service",
+            ))
+            .region(config.region.clone().unwrap_or_else(|| Region::new("us-east-1")))
+            .force_path_style(config.enforce_path_access_style);
+
+        if let Some(endpoint) = config.endpoint.as_deref() {
+            builder = builder.endpoint_url(endpoint);
+        }
+
+        if let Some(name) = config.app_name.as_deref() {
+            builder = builder.app_name(AppName::new(name.to_owned()).expect("valid app name"));
+        }
+
+        S3TemplateResolver {
+            client: Client::from_conf(builder.build()),
+            config,
+        }
+    }
+
+    /// Returns the object key that `path` points to.
+    fn key(&self, path: &Path) -> String {
+        let path = path.strip_prefix("./").unwrap_or(path).to_string_lossy();
+        match self.config.prefix.as_deref().map(|prefix| prefix.trim_matches('/')) {
+            Some(prefix) if !prefix.is_empty() => format!("{prefix}/{path}"),
+            _ => path.into_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl TemplateResolver for S3TemplateResolver {
+    async fn init(&self) -> Result<()> {
+        self.client
+            .head_bucket()
+            .bucket(&self.config.bucket)
+            .send()
+            .await
+            .with_context(|| format!("unable to access bucket `{}`", self.config.bucket))?;
+
+        info!(bucket = %self.config.bucket, prefix = ?self.config.prefix, "using templates from S3 bucket");
+        Ok(())
+    }
+
+    async fn pull(&self, path: PathBuf) -> Result<Option<String>> {
+        let key = self.key(&path);
+        let Some(bytes) = self.pull_bytes(path).await? else {
+            return Ok(None);
+        };
+
+        String::from_utf8(bytes)
+            .map(Some)
+            .map_err(|e| eyre!("object `{key}` is not valid utf-8: {e}"))
+    }
+
+    #[instrument(name = "emails.templates.s3.pull", skip_all, fields(path = %path.display()))]
+    async fn pull_bytes(&self, path: PathBuf) -> Result<Option<Vec<u8>>> {
+        let object = match self
+            .client
+            .get_object()
+            .bucket(&self.config.bucket)
+            .key(self.key(&path))
+            .send()
+            .await
+        {
+            Ok(object) => object,
+            Err(e) => {
+                let error = e.into_service_error();
+                if error.is_no_such_key() {
+                    return Ok(None);
+                }
+
+                return Err(error.into());
+            }
+        };
+
+        let bytes = object.body.collect().await?;
+        Ok(Some(bytes.into_bytes().to_vec()))
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let prefix = match self.config.prefix.as_deref().map(|prefix| prefix.trim_matches('/')) {
+            Some(prefix) if !prefix.is_empty() => format!("{prefix}/"),
+            _ => String::new(),
+        };
+
+        let mut paths = Vec::new();
+        let mut objects = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.config.bucket)
+            .prefix(&prefix)
+            .into_paginator()
+            .send();
+
+        while let Some(page) = objects.next().await {
+            for object in page?.contents() {
+                let Some(key) = object.key() else {
+                    continue;
+                };
+
+                // directory placeholders that some clients create
+                if key.ends_with('/') {
+                    continue;
+                }
+
+                paths.push(key.strip_prefix(&prefix).unwrap_or(key).to_owned());
+            }
+        }
+
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver(prefix: Option<&str>) -> S3TemplateResolver {
+        let mut config = S3StorageConfig::default();
+        config.bucket = String::from("templates");
+        config.prefix = prefix.map(String::from);
+
+        S3TemplateResolver::new(config)
+    }
+
+    #[test]
+    fn keys_are_relative_to_prefix() {
+        assert_eq!(resolver(None).key(Path::new("./welcome.html")), "welcome.html");
+        assert_eq!(resolver(Some("")).key(Path::new("welcome.html")), "welcome.html");
+        assert_eq!(
+            resolver(Some("/emails/")).key(Path::new("./welcome/index.html")),
+            "emails/welcome/index.html"
+        );
+    }
+}